@@ -0,0 +1,167 @@
+use std::net::Ipv4Addr;
+
+use hashbrown::{HashMap, HashSet};
+use pnet::packet::icmp::IcmpTypes;
+
+use crate::{prober::Rewrites, results::Results, topo::Topo, utils};
+
+/// Summary statistics of raw results.
+#[derive(Debug)]
+pub struct Summary {
+    pub destinations: usize,
+    pub replies: usize,
+    pub reached: usize,
+    pub interfaces: usize,
+    pub links: usize,
+    pub max_distance: u8,
+    pub mean_distance: f64,
+    pub median_rtt: u16,
+    /// Responders by inferred initial TTL of replies, i.e., 64, 128 or 255.
+    pub initial_ttls: Vec<(u8, usize)>,
+    /// Responders quoting time exceeded probes with TTL greater than 1, e.g., in MPLS tunnels.
+    pub quoted_ttl_anomalies: usize,
+    /// Responders right after rewrites of probes, e.g., by NATs.
+    pub rewrite_hops: Vec<(Ipv4Addr, Rewrites)>,
+    pub top_degree: Vec<(Ipv4Addr, usize)>,
+}
+
+impl Summary {
+    pub fn new(results: &Results, top: usize) -> Self {
+        let graph = Topo::build_graph(results);
+
+        let reached = results
+            .by_destination
+            .values()
+            .filter(|rs| rs.iter().any(|r| r.from_destination))
+            .count();
+
+        // distance of each destination is the farthest hop we heard from
+        let distances: Vec<u8> = results
+            .by_destination
+            .values()
+            .filter_map(|rs| rs.iter().map(|r| r.distance).max())
+            .collect();
+        let max_distance = distances.iter().copied().max().unwrap_or(0);
+        let mean_distance = if distances.is_empty() {
+            0.0
+        } else {
+            distances.iter().map(|&d| d as f64).sum::<f64>() / distances.len() as f64
+        };
+
+        let mut rtts: Vec<u16> = results
+            .by_destination
+            .values()
+            .flatten()
+            .map(|r| r.debug.rtt)
+            .collect();
+        rtts.sort_unstable();
+        let median_rtt = rtts.get(rtts.len() / 2).copied().unwrap_or(0);
+
+        let mut responder_ttls = HashMap::new();
+        let mut anomalies = HashSet::new();
+        for r in results.by_destination.values().flatten() {
+            responder_ttls
+                .entry(r.responder)
+                .or_insert_with(|| utils::infer_initial_ttl(r.reply_ttl));
+            if r.icmp_type == Some(IcmpTypes::TimeExceeded.0)
+                && matches!(r.quoted_ttl, Some(ttl) if ttl > 1)
+            {
+                anomalies.insert(r.responder);
+            }
+        }
+        let mut initial_ttls = HashMap::<u8, usize>::new();
+        for ttl in responder_ttls.values() {
+            *initial_ttls.entry(*ttl).or_default() += 1;
+        }
+        let mut initial_ttls: Vec<_> = initial_ttls.into_iter().collect();
+        initial_ttls.sort_unstable();
+
+        let mut rewrite_hops: Vec<_> = results.rewrite_hops().into_iter().collect();
+        rewrite_hops.sort_unstable_by_key(|&(addr, _)| addr);
+
+        let vantages = results.vantages();
+        let mut top_degree: Vec<_> = graph
+            .nodes()
+            .filter(|n| !vantages.contains(n))
+            .map(|n| (n, graph.neighbors(n).count()))
+            .collect();
+        top_degree.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top_degree.truncate(top);
+
+        Self {
+            destinations: results.by_destination.len(),
+            replies: results.reply_count(),
+            reached,
+            interfaces: graph.node_count().saturating_sub(vantages.len()),
+            links: graph.edge_count(),
+            max_distance,
+            mean_distance,
+            median_rtt,
+            initial_ttls,
+            quoted_ttl_anomalies: anomalies.len(),
+            rewrite_hops,
+            top_degree,
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Destinations:      {}", self.destinations);
+        println!("Replies:           {}", self.replies);
+        println!("Reached:           {}", self.reached);
+        println!("Interfaces:        {}", self.interfaces);
+        println!("Links:             {}", self.links);
+        println!("Max distance:      {}", self.max_distance);
+        println!("Mean distance:     {:.2}", self.mean_distance);
+        println!("Median RTT:        {} ms", self.median_rtt);
+        let initial_ttls: Vec<_> = self
+            .initial_ttls
+            .iter()
+            .map(|(ttl, count)| format!("{} x{}", ttl, count))
+            .collect();
+        println!("Initial TTLs:      {}", initial_ttls.join(", "));
+        println!("Quoted TTL > 1:    {}", self.quoted_ttl_anomalies);
+        println!("Rewrite hops:      {}", self.rewrite_hops.len());
+        for (addr, rewrites) in &self.rewrite_hops {
+            println!("  {:<15} {}", addr, rewrites);
+        }
+        println!("Top degree interfaces:");
+        for (addr, degree) in &self.top_degree {
+            println!("  {:<15} {}", addr, degree);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prober::ProbeResult;
+
+    #[test]
+    fn test_summary() {
+        let mut results = Results::new("10.0.0.1".parse().unwrap());
+        results.push(ProbeResult::for_test("1.0.0.1", "10.0.0.254", 1, 1));
+        results.push(ProbeResult::for_test("1.0.0.1", "1.0.0.1", 2, 10));
+        results.push(ProbeResult::for_test("2.0.0.1", "10.0.0.254", 1, 2));
+        results.push(ProbeResult::for_test("2.0.0.1", "3.0.0.1", 2, 20));
+
+        let summary = Summary::new(&results, 1);
+        assert_eq!(summary.destinations, 2);
+        assert_eq!(summary.replies, 4);
+        assert_eq!(summary.reached, 1);
+        assert_eq!(summary.interfaces, 3);
+        assert_eq!(summary.links, 3);
+        assert_eq!(summary.max_distance, 2);
+        assert_eq!(summary.median_rtt, 10);
+        assert!(summary.rewrite_hops.is_empty());
+        assert_eq!(summary.initial_ttls, vec![(64, 3)]);
+        assert_eq!(summary.quoted_ttl_anomalies, 0);
+        assert_eq!(summary.top_degree, vec![("10.0.0.254".parse().unwrap(), 3)]);
+
+        // vantage points of merged results are no interfaces
+        let mut other = Results::new("10.0.0.2".parse().unwrap());
+        other.push(ProbeResult::for_test("4.0.0.1", "10.0.1.254", 1, 1));
+        results.merge(other);
+        let summary = Summary::new(&results, 1);
+        assert_eq!(summary.interfaces, 4);
+    }
+}
@@ -0,0 +1,660 @@
+use std::{
+    hash::Hasher,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde_json::json;
+use siphasher::sip::SipHasher24;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{dcb::Confidence, error::*, opt::CoordinateOpt, results::Results, topo::Topo, OPT};
+
+type MpscTx<T> = mpsc::UnboundedSender<T>;
+type MpscRx<T> = mpsc::UnboundedReceiver<T>;
+
+type Reader = Lines<BufReader<Box<dyn AsyncRead + Unpin + Send>>>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Preprobing distance of a target, by its key.
+pub type SharedDistance = (i64, u8, Confidence);
+/// A backward stop, i.e., an interface and the destination through which it's discovered.
+pub type SharedStop = (Ipv4Addr, Ipv4Addr);
+
+const RESULTS_PER_MESSAGE: usize = 10_000;
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fingerprint of what all workers must agree on, i.e., keys of targets, `--grain`, `--seed`
+/// and `--salt`, regardless of the order of `keys`.
+pub fn fingerprint(keys: impl Iterator<Item = i64>) -> u64 {
+    let (mut sum, mut count) = (0u64, 0u64);
+    for key in keys {
+        let mut hasher = SipHasher24::new();
+        hasher.write(&key.to_le_bytes());
+        sum = sum.wrapping_add(hasher.finish());
+        count += 1;
+    }
+    let mut hasher = SipHasher24::new();
+    hasher.write(&sum.to_le_bytes());
+    hasher.write(&count.to_le_bytes());
+    hasher.write(&[OPT.grain]);
+    hasher.write(&OPT.seed.to_le_bytes());
+    hasher.write(&OPT.salt.to_le_bytes());
+    hasher.finish()
+}
+
+fn split<S>(stream: S) -> (Reader, Writer)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (r, w) = tokio::io::split(stream);
+    let r: Box<dyn AsyncRead + Unpin + Send> = Box::new(r);
+    (BufReader::new(r).lines(), Box::new(w))
+}
+
+/// Connect to `<host>:<port>` or `unix:<path>`.
+async fn connect(spec: &str) -> Result<(Reader, Writer)> {
+    #[cfg(unix)]
+    {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            return Ok(split(UnixStream::connect(path).await?));
+        }
+    }
+    Ok(split(TcpStream::connect(spec).await?))
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn bind(spec: &str) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = spec.strip_prefix("unix:") {
+                // stale socket of the previous run
+                let _ = std::fs::remove_file(path);
+                return Ok(Self::Unix(UnixListener::bind(path)?));
+            }
+        }
+        Ok(Self::Tcp(TcpListener::bind(spec).await?))
+    }
+
+    async fn accept(&self) -> Result<(Reader, Writer)> {
+        match self {
+            Self::Tcp(listener) => Ok(split(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Self::Unix(listener) => Ok(split(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Messages are JSON objects, one per line, distinguished by `type`.
+async fn send(writer: &mut Writer, message: &serde_json::Value) -> Result<()> {
+    writer
+        .write_all(format!("{}\n", message).as_bytes())
+        .await?;
+    Ok(())
+}
+
+async fn recv(reader: &mut Reader) -> Result<Option<serde_json::Value>> {
+    match reader.next_line().await? {
+        Some(line) => serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|_| Error::InvalidClusterMessage(line)),
+        None => Ok(None),
+    }
+}
+
+fn distances_message(distances: &[SharedDistance]) -> serde_json::Value {
+    let distances: Vec<_> = distances
+        .iter()
+        .map(|&(key, ttl, confidence)| json!([key, ttl, confidence as u8]))
+        .collect();
+    json!({ "type": "distances", "distances": distances })
+}
+
+fn parse_distances(message: &serde_json::Value) -> Vec<SharedDistance> {
+    let entries = message["distances"].as_array().cloned().unwrap_or_default();
+    entries
+        .iter()
+        .filter_map(|e| {
+            Some((
+                e[0].as_i64()?,
+                e[1].as_u64()? as u8,
+                Confidence::from_u8(e[2].as_u64()? as u8),
+            ))
+        })
+        .collect()
+}
+
+fn stops_message(stops: &[SharedStop]) -> serde_json::Value {
+    let stops: Vec<_> = stops
+        .iter()
+        .map(|(interface, destination)| json!([interface.to_string(), destination.to_string()]))
+        .collect();
+    json!({ "type": "stops", "stops": stops })
+}
+
+fn parse_stops(message: &serde_json::Value) -> Vec<SharedStop> {
+    let entries = message["stops"].as_array().cloned().unwrap_or_default();
+    entries
+        .iter()
+        .filter_map(|e| Some((e[0].as_str()?.parse().ok()?, e[1].as_str()?.parse().ok()?)))
+        .collect()
+}
+
+#[derive(Debug)]
+enum Outgoing {
+    Message(serde_json::Value),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Connection of a worker to the coordinator, which assigns a share of targets and relays
+/// preprobing distances and backward stops among workers.
+#[derive(Debug)]
+pub struct Worker {
+    pub index: u64,
+    pub count: u64,
+    out_tx: MpscTx<Outgoing>,
+    distances_rx: tokio::sync::Mutex<MpscRx<Vec<SharedDistance>>>,
+    stops_rx: Mutex<Option<MpscRx<Vec<SharedStop>>>>,
+}
+
+impl Worker {
+    /// Join the coordinator at `spec` with the `fingerprint` of the scan, returning once all
+    /// workers have joined.
+    pub async fn connect(spec: &str, fingerprint: u64) -> Result<Self> {
+        Self::join(spec, OPT.local_addr, fingerprint).await
+    }
+
+    async fn join(spec: &str, vantage: Ipv4Addr, fingerprint: u64) -> Result<Self> {
+        log::info!("[Worker] Connecting to the coordinator at {}...", spec);
+        let (mut reader, mut writer) = connect(spec).await?;
+        let hello = json!({
+            "type": "hello",
+            "vantage": vantage.to_string(),
+            "fingerprint": fingerprint,
+        });
+        send(&mut writer, &hello).await?;
+
+        let assign = recv(&mut reader)
+            .await?
+            .ok_or_else(|| Error::InvalidClusterMessage(String::new()))?;
+        let (index, count) = match (
+            assign["type"].as_str(),
+            assign["index"].as_u64(),
+            assign["count"].as_u64(),
+        ) {
+            (Some("assign"), Some(index), Some(count)) if index < count => (index, count),
+            (Some("reject"), ..) => {
+                let reason = assign["reason"].as_str().unwrap_or_default();
+                return Err(Error::RejectedByCoordinator(reason.to_owned()));
+            }
+            _ => return Err(Error::InvalidClusterMessage(assign.to_string())),
+        };
+        log::info!("[Worker] Assigned share {} of {}", index, count);
+
+        let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(outgoing) = out_rx.recv().await {
+                match outgoing {
+                    Outgoing::Message(message) => {
+                        if let Err(e) = send(&mut writer, &message).await {
+                            log::error!("[Worker] lost the coordinator: {}", e);
+                            break;
+                        }
+                    }
+                    Outgoing::Flush(done_tx) => {
+                        let _ = writer.flush().await;
+                        let _ = done_tx.send(());
+                    }
+                }
+            }
+        });
+
+        let (distances_tx, distances_rx) = mpsc::unbounded_channel();
+        let (stops_tx, stops_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let message = match recv(&mut reader).await {
+                    Ok(Some(message)) => message,
+                    Err(Error::InvalidClusterMessage(line)) => {
+                        log::warn!("[Worker] invalid message `{}`", line);
+                        continue;
+                    }
+                    _ => break,
+                };
+                match message["type"].as_str() {
+                    Some("distances") => {
+                        let _ = distances_tx.send(parse_distances(&message));
+                    }
+                    Some("stops") => {
+                        let _ = stops_tx.send(parse_stops(&message));
+                    }
+                    _ => log::warn!("[Worker] unexpected message: {}", message),
+                }
+            }
+            log::info!("[Worker] Disconnected from the coordinator");
+        });
+
+        Ok(Self {
+            index,
+            count,
+            out_tx,
+            distances_rx: tokio::sync::Mutex::new(distances_rx),
+            stops_rx: Mutex::new(Some(stops_rx)),
+        })
+    }
+
+    /// Whether the target of `key` is in the share of this worker.
+    pub fn owns(&self, key: i64) -> bool {
+        key.rem_euclid(self.count as i64) as u64 == self.index
+    }
+
+    fn post(&self, message: serde_json::Value) {
+        let _ = self.out_tx.send(Outgoing::Message(message));
+    }
+
+    /// Share measured distances of own targets, then wait for those of all other workers, or
+    /// until `stopped`.
+    pub async fn exchange_distances(
+        &self,
+        own: &[SharedDistance],
+        stopped: &AtomicBool,
+    ) -> Vec<SharedDistance> {
+        self.post(distances_message(own));
+        log::info!("[Worker] Waiting for distances from other workers...");
+
+        let mut distances_rx = self.distances_rx.lock().await;
+        let mut shared = Vec::new();
+        let mut waiting = self.count - 1;
+        while waiting > 0 {
+            match tokio::time::timeout(STOP_CHECK_INTERVAL, distances_rx.recv()).await {
+                Ok(Some(distances)) => {
+                    shared.extend(distances);
+                    waiting -= 1;
+                }
+                Ok(None) => break,
+                Err(_) if stopped.load(SeqCst) => {
+                    log::warn!(
+                        "[Worker] Stopped waiting for distances of {} workers",
+                        waiting
+                    );
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        log::info!("[Worker] Received {} distances", shared.len());
+        shared
+    }
+
+    pub fn share_stop(&self, interface: Ipv4Addr, destination: Ipv4Addr) {
+        self.post(stops_message(&[(interface, destination)]));
+    }
+
+    /// Backward stops shared by other workers, which can be taken only once.
+    pub fn take_stops(&self) -> Option<MpscRx<Vec<SharedStop>>> {
+        self.stops_rx.lock().unwrap().take()
+    }
+
+    /// Send raw results to the coordinator, and wait until they are written.
+    pub async fn send_results(&self, results: &Results) {
+        let lines: Vec<_> = results
+            .by_destination
+            .values()
+            .flatten()
+            .map(Results::format_line)
+            .collect();
+        log::info!(
+            "[Worker] Sending {} replies to the coordinator...",
+            lines.len()
+        );
+        for chunk in lines.chunks(RESULTS_PER_MESSAGE) {
+            self.post(json!({ "type": "results", "lines": chunk }));
+        }
+        self.post(json!({ "type": "done" }));
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let _ = self.out_tx.send(Outgoing::Flush(done_tx));
+        let _ = done_rx.await;
+    }
+}
+
+/// Wait for `--workers` to join, assign each a share of targets, relay what they share to the
+/// others, then merge their results into a single topology.
+pub async fn coordinate(opt: &CoordinateOpt) -> Result<()> {
+    let listener = Listener::bind(&opt.listen).await?;
+    log::info!(
+        "[Coordinator] Listening on {}, waiting for {} workers...",
+        opt.listen,
+        opt.workers
+    );
+    let (merged, partial) = gather(&listener, opt.workers, opt.timeout).await?;
+
+    log::info!(
+        "[Summary] Merged {} replies from {} vantage points",
+        merged.reply_count(),
+        merged.vantages().len()
+    );
+    log::info!(
+        "Saving merged raw results to {}...",
+        opt.output_results.display()
+    );
+    merged.write(&opt.output_results)?;
+
+    let comment = format!("merged from {} workers", opt.workers);
+    Topo::process_graph(&merged, &opt.output, partial, &comment).await
+}
+
+/// Coordinate `workers` joining at `listener` until all are done, or `timeout` secs after all
+/// joined, returning their merged results and whether any are missing.
+async fn gather(
+    listener: &Listener,
+    workers: u64,
+    timeout: Option<u64>,
+) -> Result<(Results, bool)> {
+    let mut readers = Vec::new();
+    let mut writers = Vec::new();
+    let mut vantages = Vec::new();
+    // of the first worker, which the others must match
+    let mut expected_fingerprint = None;
+    let (hello_tx, mut hello_rx) = mpsc::unbounded_channel();
+    while (writers.len() as u64) < workers {
+        let (reader, mut writer, hello) = tokio::select! {
+            accepted = listener.accept() => {
+                let (mut reader, writer) = accepted?;
+                let hello_tx = hello_tx.clone();
+                // apart from accepting, so that a silent client never keeps others from joining
+                tokio::spawn(async move {
+                    if let Ok(Ok(Some(hello))) =
+                        tokio::time::timeout(HELLO_TIMEOUT, recv(&mut reader)).await
+                    {
+                        let _ = hello_tx.send((reader, writer, hello));
+                    }
+                });
+                continue;
+            }
+            Some(joined) = hello_rx.recv() => joined,
+        };
+        let (vantage, fingerprint) = match hello["type"].as_str() {
+            Some("hello") => (
+                hello["vantage"]
+                    .as_str()
+                    .and_then(|v| v.parse::<Ipv4Addr>().ok()),
+                hello["fingerprint"].as_u64(),
+            ),
+            _ => (None, None),
+        };
+        match (vantage, fingerprint) {
+            (Some(vantage), Some(fingerprint)) if matches!(expected_fingerprint, Some(f) if f != fingerprint) =>
+            {
+                log::warn!(
+                    "[Coordinator] Rejected a worker from {} with a different scan",
+                    vantage
+                );
+                let reject = json!({
+                    "type": "reject",
+                    "reason": "targets, `--grain`, `--seed` or `--salt` differ from other workers",
+                });
+                let _ = send(&mut writer, &reject).await;
+            }
+            (Some(vantage), Some(fingerprint)) => {
+                expected_fingerprint = Some(fingerprint);
+                log::info!(
+                    "[Coordinator] Worker {} joined from {}",
+                    writers.len(),
+                    vantage
+                );
+                readers.push(reader);
+                writers.push(writer);
+                vantages.push(vantage);
+            }
+            _ => log::warn!("[Coordinator] Rejected a worker with hello `{}`", hello),
+        }
+    }
+
+    // a writer task for each worker, so that a slow one never stalls relaying to others
+    let count = writers.len();
+    let outs: Vec<MpscTx<serde_json::Value>> = writers
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut writer)| {
+            let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(message) = out_rx.recv().await {
+                    if let Err(e) = send(&mut writer, &message).await {
+                        log::warn!("[Coordinator] Worker {}: lost: {}", index, e);
+                        break;
+                    }
+                }
+            });
+            let _ = out_tx.send(json!({ "type": "assign", "index": index, "count": count }));
+            out_tx
+        })
+        .collect();
+
+    // (worker, message), where `None` means the worker is gone
+    let (in_tx, mut in_rx) = mpsc::unbounded_channel();
+    for (index, mut reader) in readers.into_iter().enumerate() {
+        let in_tx = in_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                match recv(&mut reader).await {
+                    Ok(Some(message)) => {
+                        let _ = in_tx.send((index, Some(message)));
+                    }
+                    Err(Error::InvalidClusterMessage(line)) => {
+                        log::warn!("[Coordinator] Worker {}: invalid message `{}`", index, line)
+                    }
+                    _ => break,
+                }
+            }
+            let _ = in_tx.send((index, None));
+        });
+    }
+    drop(in_tx);
+
+    let mut results: Vec<_> = vantages.iter().map(|&v| Results::new(v)).collect();
+    let mut shared_distances = vec![false; count];
+    let mut done = vec![false; count];
+    let mut partial = false;
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    let deadline = tokio::time::sleep(Duration::from_secs(timeout.unwrap_or(0)));
+    tokio::pin!(ctrl_c, deadline);
+    while done.iter().any(|d| !d) {
+        let received = tokio::select! {
+            received = in_rx.recv() => received,
+            _ = &mut ctrl_c => {
+                log::warn!("[Coordinator] Interrupted, merging results received so far");
+                partial = true;
+                break;
+            }
+            _ = &mut deadline, if timeout.is_some() => {
+                log::warn!("[Coordinator] Timed out, merging results received so far");
+                partial = true;
+                break;
+            }
+        };
+        let (index, message) = match received {
+            Some(received) => received,
+            None => break,
+        };
+        let message = match message {
+            Some(message) => message,
+            None => {
+                if !done[index] {
+                    log::warn!("[Coordinator] Worker {} left before done", index);
+                    done[index] = true;
+                    partial = true;
+                    // never keep others waiting for its distances
+                    if !shared_distances[index] {
+                        shared_distances[index] = true;
+                        relay(&outs, index, &distances_message(&[]));
+                    }
+                }
+                continue;
+            }
+        };
+
+        match message["type"].as_str() {
+            Some("distances") => {
+                shared_distances[index] = true;
+                relay(&outs, index, &message);
+            }
+            Some("stops") => relay(&outs, index, &message),
+            Some("results") => {
+                let lines = message["lines"].as_array().cloned().unwrap_or_default();
+                for line in lines.iter().filter_map(|l| l.as_str()) {
+                    match Results::parse_line(line) {
+                        Some(result) => results[index].push(result),
+                        None => log::warn!("[Coordinator] Invalid result line `{}`", line),
+                    }
+                }
+            }
+            Some("done") => {
+                log::info!(
+                    "[Coordinator] Worker {} is done with {} replies",
+                    index,
+                    results[index].reply_count()
+                );
+                done[index] = true;
+            }
+            _ => log::warn!("[Coordinator] Unexpected message: {}", message),
+        }
+    }
+
+    let mut results = results.into_iter();
+    let mut merged = results.next().unwrap();
+    for other in results {
+        merged.merge(other);
+    }
+    Ok((merged, partial))
+}
+
+/// Send a message from worker `from` to all the others, ignoring those gone.
+fn relay(outs: &[MpscTx<serde_json::Value>], from: usize, message: &serde_json::Value) {
+    for (index, out) in outs.iter().enumerate() {
+        if index != from {
+            let _ = out.send(message.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prober::ProbeResult;
+
+    #[test]
+    fn test_messages() {
+        let distances = vec![
+            (-1, 12, Confidence::Exact),
+            (42, 32, Confidence::LowerBound),
+        ];
+        assert_eq!(parse_distances(&distances_message(&distances)), distances);
+
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        let stops = vec![(addr("10.0.0.1"), addr("1.2.3.4"))];
+        assert_eq!(parse_stops(&stops_message(&stops)), stops);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_coordinate() {
+        let path = crate::utils::test_path("cluster.sock");
+        let spec = format!("unix:{}", path.display());
+        let listener = Listener::bind(&spec).await.unwrap();
+        let coordinator = tokio::spawn(async move { gather(&listener, 2, Some(10)).await });
+        // never says hello, yet keeps no worker from joining
+        let _silent = UnixStream::connect(&path).await.unwrap();
+
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        let first = {
+            let spec = spec.clone();
+            tokio::spawn(async move { Worker::join(&spec, addr("10.0.0.1"), 42).await })
+        };
+        // the first worker sets the fingerprint
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let rejected = Worker::join(&spec, addr("10.0.0.3"), 43).await;
+        assert!(matches!(rejected, Err(Error::RejectedByCoordinator(_))));
+        let second = Worker::join(&spec, addr("10.0.0.2"), 42).await.unwrap();
+        let first = first.await.unwrap().unwrap();
+
+        // shares are interleaved
+        assert_eq!(first.count, 2);
+        assert_ne!(first.index, second.index);
+        assert!((0..10).all(|key| first.owns(key) != second.owns(key)));
+
+        let stopped = AtomicBool::new(false);
+        let (first_shared, second_shared) = tokio::join!(
+            first.exchange_distances(&[(0, 12, Confidence::Exact)], &stopped),
+            second.exchange_distances(&[(1, 14, Confidence::LowerBound)], &stopped),
+        );
+        assert_eq!(first_shared, vec![(1, 14, Confidence::LowerBound)]);
+        assert_eq!(second_shared, vec![(0, 12, Confidence::Exact)]);
+
+        let mut stops_rx = second.take_stops().unwrap();
+        first.share_stop(addr("192.168.0.1"), addr("1.2.3.4"));
+        assert_eq!(
+            stops_rx.recv().await.unwrap(),
+            vec![(addr("192.168.0.1"), addr("1.2.3.4"))]
+        );
+
+        for (worker, dst, responder, distance, rtt) in &[
+            (&first, "1.2.3.4", "192.168.0.1", 3, 10),
+            (&second, "5.6.7.8", "192.168.0.2", 4, 20),
+        ] {
+            let mut results = Results::new(addr("0.0.0.0"));
+            results.push(ProbeResult::for_test(dst, responder, *distance, *rtt));
+            worker.send_results(&results).await;
+        }
+        let (merged, partial) = coordinator.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!partial);
+        assert_eq!(merged.reply_count(), 2);
+        assert_eq!(merged.vantage, addr("10.0.0.1"));
+        assert_eq!(merged.vantage_of(addr("5.6.7.8")), addr("10.0.0.2"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_exchange_distances_stopped() {
+        let path = crate::utils::test_path("cluster_stop.sock");
+        let spec = format!("unix:{}", path.display());
+        let listener = Listener::bind(&spec).await.unwrap();
+        let coordinator = tokio::spawn(async move { gather(&listener, 2, Some(1)).await });
+
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        let (first, second) = tokio::join!(
+            Worker::join(&spec, addr("10.0.0.1"), 42),
+            Worker::join(&spec, addr("10.0.0.2"), 42),
+        );
+        let (first, _second) = (first.unwrap(), second.unwrap());
+
+        // the other worker never shares its distances
+        let stopped = AtomicBool::new(true);
+        assert!(first.exchange_distances(&[], &stopped).await.is_empty());
+        // nor is done
+        let (_, partial) = coordinator.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(partial);
+    }
+}
@@ -0,0 +1,281 @@
+use std::path::Path;
+
+use structopt::{clap::ErrorKind, StructOpt};
+use toml::Value;
+
+use crate::{error::*, opt::Opt};
+
+/// Options from a TOML config file, keyed by the long names of command line flags. Top-level
+/// keys apply to all runs, while those in `[profiles.<name>]` apply if `--profile <name>` is
+/// given, e.g.,
+///
+/// ```toml
+/// probing-rate = 100000
+///
+/// [profiles.internet-24]
+/// targets = "0.0.0.0/0"
+/// grain = 8
+/// ```
+///
+/// Boolean flags may also be set by their negation, e.g., `shuffle = false` for `no-shuffle = true`.
+/// Options from the command line take precedence, where flags set in the config are turned off
+/// by their negation, e.g., `--no-router-only` for `router-only = true`.
+#[derive(Debug)]
+pub struct Config {
+    args: Vec<String>,
+    targets: Option<String>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path, profile: Option<&str>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content, profile)
+    }
+
+    pub fn parse(content: &str, profile: Option<&str>) -> Result<Self> {
+        let mut root = match content.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => return Err(Error::InvalidConfig("not a table".to_owned())),
+            Err(e) => return Err(Error::InvalidConfig(e.to_string())),
+        };
+
+        let mut profiles = match root.remove("profiles") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => return Err(Error::InvalidConfig("`profiles` is not a table".to_owned())),
+            None => Default::default(),
+        };
+
+        let mut config = Self {
+            args: Vec::new(),
+            targets: None,
+        };
+        config.extend(root)?;
+
+        if let Some(profile) = profile {
+            match profiles.remove(profile) {
+                Some(Value::Table(table)) => config.extend(table)?,
+                Some(_) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "profile `{}` is not a table",
+                        profile
+                    )))
+                }
+                None => {
+                    let names: Vec<_> = profiles.keys().map(|k| k.as_str()).collect();
+                    return Err(Error::InvalidConfig(format!(
+                        "no profile `{}`, available: [{}]",
+                        profile,
+                        names.join(", ")
+                    )));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn extend(&mut self, table: toml::value::Table) -> Result<()> {
+        for (key, value) in table {
+            let value = match value {
+                Value::String(s) => s,
+                Value::Integer(i) => i.to_string(),
+                Value::Float(f) => f.to_string(),
+                Value::Boolean(value) => {
+                    self.push_flag(&key, value)?;
+                    continue;
+                }
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "unsupported value for `{}`",
+                        key
+                    )))
+                }
+            };
+            if key == "targets" {
+                self.targets = Some(value);
+            } else {
+                self.args.push(format!("--{}={}", key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set a boolean flag, or its negation, overriding both of them set before, e.g., in the
+    /// top-level of a profile.
+    fn push_flag(&mut self, key: &str, value: bool) -> Result<()> {
+        let negation = negation(key);
+        let (flag, negation) = (format!("--{}", key), format!("--{}", negation));
+        self.args.retain(|arg| *arg != flag && *arg != negation);
+        if is_flag(&flag) {
+            if value {
+                self.args.push(flag);
+            }
+        } else if is_flag(&negation) {
+            if !value {
+                self.args.push(negation);
+            }
+        } else {
+            return Err(Error::InvalidConfig(format!("`{}` is not a flag", key)));
+        }
+        Ok(())
+    }
+}
+
+/// `no-x` for `x`, and `x` for `no-x`.
+fn negation(name: &str) -> String {
+    match name.strip_prefix("no-") {
+        Some(name) => name.to_owned(),
+        None => format!("no-{}", name),
+    }
+}
+
+/// Whether `arg` is a flag of `scan` taking no value, by parsing it alone with a target, which
+/// would be taken as the value of an option.
+fn is_flag(arg: &str) -> bool {
+    Opt::clap()
+        .get_matches_from_safe(["", arg, "0.0.0.0/32"].iter().copied())
+        .is_ok()
+}
+
+fn find_arg(args: &[String], name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == name {
+            args.get(i + 1).cloned()
+        } else if arg.starts_with(&prefix) {
+            Some(arg[prefix.len()..].to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Merge options from `--config` into command line arguments, where the latter take
+/// precedence. Options from the config are validated up front.
+pub fn merge_args(args: Vec<String>) -> Result<Vec<String>> {
+    let path = match find_arg(&args, "--config") {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let profile = find_arg(&args, "--profile");
+    let config = Config::from_file(path.as_ref(), profile.as_deref())?;
+
+    // validate options from the config alone, with a placeholder target
+    let mut config_args = vec![args[0].clone()];
+    config_args.extend(config.args.iter().cloned());
+    config_args.push(
+        config
+            .targets
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0/32".to_owned()),
+    );
+    if let Err(e) = Opt::clap().get_matches_from_safe(&config_args) {
+        let message = e.message.lines().next().unwrap_or_default();
+        return Err(Error::InvalidConfig(format!(
+            "{} in `{}`",
+            message.trim_start_matches("error: "),
+            path
+        )));
+    }
+
+    // negations of flags on the command line turn those in the config off
+    let (negations, args): (Vec<_>, Vec<_>) = args.into_iter().enumerate().partition(|(i, arg)| {
+        *i > 0
+            && arg.starts_with("--")
+            && !arg.contains('=')
+            && !is_flag(arg)
+            && is_flag(&format!("--{}", negation(&arg[2..])))
+    });
+    let args: Vec<_> = args.into_iter().map(|(_, arg)| arg).collect();
+    let mut config_args = config.args;
+    for (_, arg) in negations {
+        let flag = format!("--{}", negation(&arg[2..]));
+        config_args.retain(|arg| *arg != flag);
+    }
+
+    // command line arguments come last so that they override the config
+    let mut merged = vec![args[0].clone()];
+    merged.extend(config_args);
+    if let Some(targets) = config.targets {
+        match Opt::clap().get_matches_from_safe(&args) {
+            Err(e) if e.kind == ErrorKind::MissingRequiredArgument => merged.push(targets),
+            _ => {}
+        }
+    }
+    merged.extend(args.into_iter().skip(1));
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        probing-rate = 100000
+        router-only = true
+        no-dot = false
+        shuffle = false
+
+        [profiles.internet-24]
+        targets = "0.0.0.0/0"
+        grain = 8
+
+        [profiles.campus-scan]
+        targets = "10.0.0.0/8"
+        grain = 0
+        probing-rate = 1000
+    "#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(CONFIG, None).unwrap();
+        assert_eq!(
+            config.args,
+            vec!["--probing-rate=100000", "--router-only", "--no-shuffle"]
+        );
+        assert_eq!(config.targets, None);
+
+        let config = Config::parse(CONFIG, Some("campus-scan")).unwrap();
+        assert_eq!(
+            config.args,
+            vec![
+                "--probing-rate=100000",
+                "--router-only",
+                "--no-shuffle",
+                "--grain=0",
+                "--probing-rate=1000"
+            ]
+        );
+        assert_eq!(config.targets, Some("10.0.0.0/8".to_owned()));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Config::parse(CONFIG, Some("no-such-profile")).is_err());
+        assert!(Config::parse("grain = [8]", None).is_err());
+        assert!(Config::parse("grain = ", None).is_err());
+        assert!(Config::parse("grain = true", None).is_err());
+        assert!(Config::parse("no-such-flag = false", None).is_err());
+    }
+
+    #[test]
+    fn test_override() {
+        let config = "router-only = true\n[profiles.p]\nrouter-only = false\n";
+        let config = Config::parse(config, Some("p")).unwrap();
+        assert!(config.args.is_empty());
+
+        let path = crate::utils::test_path("config.toml");
+        std::fs::write(&path, "router-only = true\ngrain = 4\n").unwrap();
+        let args = [
+            "fr",
+            "--config",
+            path.to_str().unwrap(),
+            "--no-router-only",
+            "1.2.3.4/32",
+        ];
+        let merged = merge_args(args.iter().map(|&s| s.to_owned()).collect()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!merged.iter().any(|arg| arg.contains("router-only")));
+        assert!(merged.contains(&"--grain=4".to_owned()));
+    }
+}
@@ -0,0 +1,230 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering::SeqCst},
+    Mutex,
+};
+
+use crate::{
+    metrics::{PacketCounters, METRICS},
+    prober::ProbePhase,
+    utils, OPT,
+};
+
+lazy_static! {
+    /// Progress of the running scan and knobs to adjust it on the fly.
+    pub static ref CONTROL: Control = Control::new();
+}
+
+/// Stage of the running scan.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stage {
+    Idle = 0,
+    Preprobing = 1,
+    Probing = 2,
+    Waiting = 3,
+    Done = 4,
+}
+
+impl Stage {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Stage::Preprobing,
+            2 => Stage::Probing,
+            3 => Stage::Waiting,
+            4 => Stage::Done,
+            _ => Stage::Idle,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Idle => "idle",
+            Stage::Preprobing => "preprobing",
+            Stage::Probing => "probing",
+            Stage::Waiting => "waiting",
+            Stage::Done => "done",
+        }
+    }
+}
+
+/// Events in the last complete second, e.g., probes sent, which drops to 0 once they stop.
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    second: AtomicU64,
+    count: AtomicU64,
+    last_count: AtomicU64,
+}
+
+impl RateMeter {
+    pub fn tick(&self) {
+        self.tick_at(utils::timestamp_ms() / 1000);
+    }
+
+    fn tick_at(&self, second: u64) {
+        let last = self.second.swap(second, SeqCst);
+        if second != last {
+            let count = self.count.swap(0, SeqCst);
+            let count = if second == last + 1 { count } else { 0 };
+            self.last_count.store(count, SeqCst);
+        }
+        self.count.fetch_add(1, SeqCst);
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate_at(utils::timestamp_ms() / 1000)
+    }
+
+    fn rate_at(&self, second: u64) -> u64 {
+        let last = self.second.load(SeqCst);
+        if second == last {
+            self.last_count.load(SeqCst)
+        } else if second == last + 1 {
+            self.count.load(SeqCst)
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Control {
+    stage: AtomicU8,
+    round: AtomicUsize,
+    total_keys: AtomicUsize,
+    remaining_keys: AtomicUsize,
+    start_time_ms: AtomicU64,
+
+    // `METRICS` counters when the current phase began
+    phase_base: Mutex<Option<(ProbePhase, PacketCounters)>>,
+    // probes actually sent
+    pub sending_rate: RateMeter,
+
+    paused: AtomicBool,
+    probing_rate: AtomicU64,
+}
+
+impl Control {
+    fn new() -> Self {
+        Self {
+            stage: AtomicU8::new(Stage::Idle as u8),
+            round: AtomicUsize::new(0),
+            total_keys: AtomicUsize::new(0),
+            remaining_keys: AtomicUsize::new(0),
+            start_time_ms: AtomicU64::new(utils::timestamp_ms()),
+            phase_base: Mutex::new(None),
+            sending_rate: RateMeter::default(),
+            paused: AtomicBool::new(false),
+            probing_rate: AtomicU64::new(OPT.probing_rate),
+        }
+    }
+
+    pub fn stage(&self) -> Stage {
+        Stage::from_u8(self.stage.load(SeqCst))
+    }
+
+    pub fn set_stage(&self, stage: Stage) {
+        self.stage.store(stage as u8, SeqCst);
+    }
+
+    /// Enter a new phase, which has `total_keys` targets to probe in the first round.
+    pub fn begin_phase(&self, stage: Stage, total_keys: usize) {
+        self.set_stage(stage);
+        self.round.store(0, SeqCst);
+        self.total_keys.store(total_keys, SeqCst);
+        self.remaining_keys.store(total_keys, SeqCst);
+        let phase = match stage {
+            Stage::Preprobing => ProbePhase::Pre,
+            _ => ProbePhase::Main,
+        };
+        *self.phase_base.lock().unwrap() = Some((phase, METRICS.counters(phase)));
+    }
+
+    /// Packet counters of the current phase.
+    pub fn counters(&self) -> PacketCounters {
+        match *self.phase_base.lock().unwrap() {
+            Some((phase, base)) => METRICS.counters(phase).since(base),
+            None => PacketCounters::default(),
+        }
+    }
+
+    pub fn set_round(&self, round: usize, remaining_keys: usize) {
+        self.round.store(round, SeqCst);
+        self.remaining_keys.store(remaining_keys, SeqCst);
+    }
+
+    pub fn round(&self) -> usize {
+        self.round.load(SeqCst)
+    }
+
+    pub fn total_keys(&self) -> usize {
+        self.total_keys.load(SeqCst)
+    }
+
+    pub fn remaining_keys(&self) -> usize {
+        self.remaining_keys.load(SeqCst)
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        utils::timestamp_ms() - self.start_time_ms.load(SeqCst)
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, SeqCst) != paused {
+            log::warn!("Scan is {}", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    /// Probes per second, `u64::MAX` if unlimited.
+    pub fn probing_rate(&self) -> u64 {
+        self.probing_rate.load(SeqCst)
+    }
+
+    pub fn set_probing_rate(&self, rate: u64) {
+        log::warn!("Probing rate is changed to {}", rate);
+        let rate = if rate == 0 { u64::MAX } else { rate };
+        self.probing_rate.store(rate, SeqCst);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let rate = match self.probing_rate() {
+            u64::MAX => 0,
+            rate => rate,
+        };
+        let counters = self.counters();
+        serde_json::json!({
+            "stage": self.stage().name(),
+            "round": self.round(),
+            "total_keys": self.total_keys(),
+            "remaining_keys": self.remaining_keys(),
+            "elapsed_ms": self.elapsed_ms(),
+            "sent": counters.sent,
+            "recv": counters.recv,
+            "current_rate": self.sending_rate.rate(),
+            "probing_rate": rate,
+            "paused": self.paused(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_meter() {
+        let meter = RateMeter::default();
+        for _ in 0..3 {
+            meter.tick_at(100);
+        }
+        meter.tick_at(101);
+        assert_eq!(meter.rate_at(101), 3);
+        assert_eq!(meter.rate_at(102), 1);
+        // stopped sending
+        assert_eq!(meter.rate_at(103), 0);
+        meter.tick_at(110);
+        assert_eq!(meter.rate_at(110), 0);
+    }
+}
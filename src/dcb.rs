@@ -1,20 +1,62 @@
 use std::{
     net::Ipv4Addr,
-    sync::atomic::{AtomicBool, AtomicU8, Ordering::SeqCst},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering::SeqCst},
 };
 
 use crate::OPT;
 
+/// How much the split ttl of a destination is trusted, from the least to the most.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Confidence {
+    None = 0,
+    /// Measured distance of a nearby destination.
+    Proximity = 1,
+    /// Time exceeded at the preprobing ttl, thus the destination is even farther.
+    LowerBound = 2,
+    /// Inferred from the reply ttl with a guessed initial ttl.
+    ReplyTtl = 3,
+    /// Measured by the quoted ttl of a reply from the destination.
+    Exact = 4,
+}
+
+impl Confidence {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Confidence::Proximity,
+            2 => Confidence::LowerBound,
+            3 => Confidence::ReplyTtl,
+            4 => Confidence::Exact,
+            _ => Confidence::None,
+        }
+    }
+}
+
+/// Why backward probing of a destination is over.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BackwardStopReason {
+    /// Reached an interface already discovered, i.e., converged with another path.
+    Converged,
+    /// Probed all the way down to ttl 1.
+    Exhausted,
+    /// Neither, e.g., the scan is stopped early.
+    Interrupted,
+}
+
 #[derive(Debug)]
 pub struct DstCtrlBlock {
     pub addr: Ipv4Addr,
     initial_ttl: AtomicU8,
-    accurate_distance: AtomicBool,
+    confidence: AtomicU8,
     next_backward_hop: AtomicU8,
     next_forward_hop: AtomicU8,
     forward_horizon: AtomicU8,
     backward_count: AtomicU8,
 
+    // redundancy removal
+    backward_stop_ttl: AtomicU8,
+    converged_with: AtomicU32,
+    saved_probes: AtomicU8,
+
     pub preprobed: AtomicBool,
 }
 
@@ -23,26 +65,32 @@ impl DstCtrlBlock {
         DstCtrlBlock {
             addr,
             initial_ttl: AtomicU8::new(initial_ttl),
-            accurate_distance: AtomicBool::new(false),
+            confidence: AtomicU8::new(Confidence::None as u8),
             next_backward_hop: AtomicU8::new(initial_ttl),
             next_forward_hop: AtomicU8::new(initial_ttl + 1),
             forward_horizon: AtomicU8::new(initial_ttl),
             backward_count: AtomicU8::new(0),
 
+            backward_stop_ttl: AtomicU8::new(0),
+            converged_with: AtomicU32::new(0),
+            saved_probes: AtomicU8::new(0),
+
             preprobed: AtomicBool::new(false),
         }
     }
 
-    pub fn update_split_ttl(&self, new_ttl: u8, accurate: bool) {
-        log::trace!("SPLIT_TTL: {} => {}", self.addr, new_ttl);
-        if self.accurate_distance.load(SeqCst) {
+    /// Update the split ttl unless the current one is more trusted, or already exact.
+    pub fn update_split_ttl(&self, new_ttl: u8, confidence: Confidence) {
+        log::trace!("SPLIT_TTL: {} => {} ({:?})", self.addr, new_ttl, confidence);
+        let current = self.confidence();
+        if current == Confidence::Exact || confidence < current {
             return;
         }
         self.initial_ttl.store(new_ttl, SeqCst);
         self.next_backward_hop.store(new_ttl, SeqCst);
         self.next_forward_hop.store(new_ttl + 1, SeqCst);
         self.forward_horizon.store(new_ttl, SeqCst);
-        self.accurate_distance.store(accurate, SeqCst);
+        self.confidence.store(confidence as u8, SeqCst);
 
         self.preprobed.store(true, SeqCst);
     }
@@ -53,6 +101,10 @@ impl DstCtrlBlock {
         self.initial_ttl.load(SeqCst)
     }
 
+    pub fn confidence(&self) -> Confidence {
+        Confidence::from_u8(self.confidence.load(SeqCst))
+    }
+
     pub fn pull_backward_task(&self) -> Option<u8> {
         let result = self.next_backward_hop.fetch_update(SeqCst, SeqCst, |x| {
             if x > 0 {
@@ -96,16 +148,52 @@ impl DstCtrlBlock {
         });
     }
 
-    pub fn stop_backward(&self) {
+    /// Stop backward probing, returning the number of probes saved.
+    pub fn stop_backward(&self) -> u8 {
         if OPT.plot_optimized {
             if self.backward_count.load(SeqCst) >= 2 {
-                self.next_backward_hop.fetch_min(0, SeqCst);
+                self.next_backward_hop.fetch_min(0, SeqCst)
+            } else {
+                0
             }
         } else {
-            self.next_backward_hop.fetch_min(0, SeqCst);
+            self.next_backward_hop.fetch_min(0, SeqCst)
         }
     }
 
+    /// Stop backward probing since the path converges at `ttl` with the one towards
+    /// `converged_with`, and record it.
+    pub fn converge_backward(&self, ttl: u8, converged_with: Ipv4Addr) {
+        let saved = self.stop_backward();
+        if saved > 0 {
+            self.backward_stop_ttl.store(ttl, SeqCst);
+            self.converged_with.store(converged_with.into(), SeqCst);
+            self.saved_probes.fetch_add(saved, SeqCst);
+        }
+    }
+
+    pub fn backward_stop_reason(&self) -> BackwardStopReason {
+        if self.backward_stop_ttl.load(SeqCst) > 0 {
+            BackwardStopReason::Converged
+        } else if self.next_backward_hop.load(SeqCst) == 0 {
+            BackwardStopReason::Exhausted
+        } else {
+            BackwardStopReason::Interrupted
+        }
+    }
+
+    pub fn backward_stop_ttl(&self) -> u8 {
+        self.backward_stop_ttl.load(SeqCst)
+    }
+
+    pub fn converged_with(&self) -> Ipv4Addr {
+        self.converged_with.load(SeqCst).into()
+    }
+
+    pub fn saved_probes(&self) -> u8 {
+        self.saved_probes.load(SeqCst)
+    }
+
     pub fn stop_forward(&self) {
         self.forward_horizon.fetch_min(0, SeqCst);
     }
@@ -146,6 +234,35 @@ mod test {
         assert_eq!(dcb.pull_backward_task(), None);
     }
 
+    #[test]
+    fn test_update_split_ttl() {
+        let dcb = DstCtrlBlock::new(*IP, 16);
+        dcb.update_split_ttl(10, Confidence::ReplyTtl);
+        assert_eq!(dcb.initial_ttl(), 10);
+        dcb.update_split_ttl(12, Confidence::Proximity);
+        assert_eq!(dcb.initial_ttl(), 10);
+        dcb.update_split_ttl(11, Confidence::Exact);
+        assert_eq!(dcb.initial_ttl(), 11);
+        dcb.update_split_ttl(13, Confidence::Exact);
+        assert_eq!(dcb.initial_ttl(), 11);
+        assert_eq!(dcb.confidence(), Confidence::Exact);
+        assert_eq!(dcb.pull_backward_task(), Some(11));
+    }
+
+    #[test]
+    fn test_converge_backward() {
+        let dcb = DstCtrlBlock::new(*IP, 5);
+        let other = "1.2.3.4".parse().unwrap();
+        assert_eq!(dcb.pull_backward_task(), Some(5));
+        assert_eq!(dcb.backward_stop_reason(), BackwardStopReason::Interrupted);
+        dcb.converge_backward(5, other);
+        assert_eq!(dcb.pull_backward_task(), None);
+        assert_eq!(dcb.backward_stop_reason(), BackwardStopReason::Converged);
+        assert_eq!(dcb.backward_stop_ttl(), 5);
+        assert_eq!(dcb.converged_with(), other);
+        assert_eq!(dcb.saved_probes(), 4);
+    }
+
     #[test]
     fn test_stop_forward_task() {
         let dcb = DstCtrlBlock::new(*IP, 3);
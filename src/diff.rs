@@ -0,0 +1,386 @@
+use std::{fmt::Write as _, net::Ipv4Addr, path::Path};
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::{error::*, opt::DiffFormat, results::Results, topo::Topo};
+
+type Link = (Ipv4Addr, Ipv4Addr);
+
+/// A destination whose route changed, with hops ordered by distance, where several responders
+/// may be at one distance.
+#[derive(Debug)]
+pub struct PathChange {
+    pub destination: Ipv4Addr,
+    pub old: Vec<(u8, Ipv4Addr)>,
+    pub new: Vec<(u8, Ipv4Addr)>,
+}
+
+/// Median RTT towards an interface in both scans.
+#[derive(Debug)]
+pub struct RttShift {
+    pub interface: Ipv4Addr,
+    pub old_rtt: u16,
+    pub new_rtt: u16,
+}
+
+impl RttShift {
+    pub fn shift(&self) -> i32 {
+        self.new_rtt as i32 - self.old_rtt as i32
+    }
+}
+
+/// Differences between the topologies of two scans.
+#[derive(Debug, Default)]
+pub struct TopoDiff {
+    pub added_interfaces: Vec<Ipv4Addr>,
+    pub removed_interfaces: Vec<Ipv4Addr>,
+    pub added_links: Vec<Link>,
+    pub removed_links: Vec<Link>,
+    pub common_links: Vec<Link>,
+    pub path_changes: Vec<PathChange>,
+    pub rtt_shifts: Vec<RttShift>,
+}
+
+fn links(results: &Results) -> HashSet<Link> {
+    Topo::build_graph(results)
+        .all_edges()
+        .map(|(a, b, _)| (a.min(b), a.max(b)))
+        .collect()
+}
+
+fn interfaces(results: &Results) -> HashSet<Ipv4Addr> {
+    results
+        .by_destination
+        .values()
+        .flatten()
+        .map(|r| r.responder)
+        .collect()
+}
+
+fn sorted<T: Ord>(mut v: Vec<T>) -> Vec<T> {
+    v.sort_unstable();
+    v
+}
+
+fn sorted_difference<T: Copy + Ord + std::hash::Hash>(a: &HashSet<T>, b: &HashSet<T>) -> Vec<T> {
+    sorted(a.difference(b).copied().collect())
+}
+
+fn paths(results: &Results) -> HashMap<Ipv4Addr, Vec<(u8, Ipv4Addr)>> {
+    results
+        .by_destination
+        .iter()
+        .map(|(&dst, rs)| {
+            let mut path: Vec<_> = rs.iter().map(|r| (r.distance, r.responder)).collect();
+            path.sort_unstable();
+            path.dedup();
+            (dst, path)
+        })
+        .collect()
+}
+
+fn responders_by_distance(path: &[(u8, Ipv4Addr)]) -> HashMap<u8, HashSet<Ipv4Addr>> {
+    let mut responders = HashMap::<u8, HashSet<Ipv4Addr>>::new();
+    for &(distance, hop) in path {
+        responders.entry(distance).or_default().insert(hop);
+    }
+    responders
+}
+
+/// Whether two paths share no responder at any distance observed in both, so that neither
+/// lost replies nor load balancing among responders are reported as changes.
+fn path_changed(old: &[(u8, Ipv4Addr)], new: &[(u8, Ipv4Addr)]) -> bool {
+    let new = responders_by_distance(new);
+    responders_by_distance(old)
+        .iter()
+        .any(|(distance, hops)| matches!(new.get(distance), Some(h) if h.is_disjoint(hops)))
+}
+
+fn median_rtts(results: &Results) -> HashMap<Ipv4Addr, u16> {
+    let mut rtts = HashMap::<Ipv4Addr, Vec<u16>>::new();
+    for r in results.by_destination.values().flatten() {
+        rtts.entry(r.responder).or_default().push(r.debug.rtt);
+    }
+    rtts.into_iter()
+        .map(|(addr, mut v)| {
+            v.sort_unstable();
+            (addr, v[v.len() / 2])
+        })
+        .collect()
+}
+
+impl TopoDiff {
+    /// Compare two scans, where RTT shifts less than `rtt_threshold` milliseconds are ignored.
+    pub fn new(old: &Results, new: &Results, rtt_threshold: u16) -> Self {
+        let (old_interfaces, new_interfaces) = (interfaces(old), interfaces(new));
+        let (old_links, new_links) = (links(old), links(new));
+
+        let new_paths = paths(new);
+        let mut path_changes: Vec<_> = paths(old)
+            .into_iter()
+            .filter_map(|(destination, old)| {
+                let new = new_paths.get(&destination)?;
+                if path_changed(&old, new) {
+                    Some(PathChange {
+                        destination,
+                        old,
+                        new: new.clone(),
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        path_changes.sort_unstable_by_key(|c| c.destination);
+
+        let new_rtts = median_rtts(new);
+        let mut rtt_shifts: Vec<_> = median_rtts(old)
+            .into_iter()
+            .filter_map(|(interface, old_rtt)| {
+                let new_rtt = *new_rtts.get(&interface)?;
+                let shift = RttShift {
+                    interface,
+                    old_rtt,
+                    new_rtt,
+                };
+                if shift.shift().abs() >= rtt_threshold as i32 {
+                    Some(shift)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        rtt_shifts.sort_unstable_by_key(|s| (-s.shift().abs(), s.interface));
+
+        Self {
+            added_interfaces: sorted_difference(&new_interfaces, &old_interfaces),
+            removed_interfaces: sorted_difference(&old_interfaces, &new_interfaces),
+            added_links: sorted_difference(&new_links, &old_links),
+            removed_links: sorted_difference(&old_links, &new_links),
+            common_links: sorted(old_links.intersection(&new_links).copied().collect()),
+            path_changes,
+            rtt_shifts,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_interfaces.is_empty()
+            && self.removed_interfaces.is_empty()
+            && self.added_links.is_empty()
+            && self.removed_links.is_empty()
+            && self.path_changes.is_empty()
+            && self.rtt_shifts.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        let hops = |path: &[(u8, Ipv4Addr)]| {
+            let hops: Vec<_> = path.iter().map(|(d, h)| format!("{}:{}", d, h)).collect();
+            hops.join(" ")
+        };
+
+        let mut s = String::new();
+        let _ = writeln!(
+            s,
+            "Interfaces: +{} -{}",
+            self.added_interfaces.len(),
+            self.removed_interfaces.len()
+        );
+        for addr in &self.added_interfaces {
+            let _ = writeln!(s, "  + {}", addr);
+        }
+        for addr in &self.removed_interfaces {
+            let _ = writeln!(s, "  - {}", addr);
+        }
+        let _ = writeln!(
+            s,
+            "Links: +{} -{}",
+            self.added_links.len(),
+            self.removed_links.len()
+        );
+        for (a, b) in &self.added_links {
+            let _ = writeln!(s, "  + {} -- {}", a, b);
+        }
+        for (a, b) in &self.removed_links {
+            let _ = writeln!(s, "  - {} -- {}", a, b);
+        }
+        let _ = writeln!(s, "Path changes: {}", self.path_changes.len());
+        for c in &self.path_changes {
+            let _ = writeln!(s, "  {}", c.destination);
+            let _ = writeln!(s, "    - {}", hops(&c.old));
+            let _ = writeln!(s, "    + {}", hops(&c.new));
+        }
+        let _ = writeln!(s, "RTT shifts: {}", self.rtt_shifts.len());
+        for r in &self.rtt_shifts {
+            let _ = writeln!(
+                s,
+                "  {:<15} {} ms -> {} ms ({:+})",
+                r.interface,
+                r.old_rtt,
+                r.new_rtt,
+                r.shift()
+            );
+        }
+        s
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let addrs = |v: &[Ipv4Addr]| v.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let links = |v: &[Link]| {
+            v.iter()
+                .map(|(a, b)| vec![a.to_string(), b.to_string()])
+                .collect::<Vec<_>>()
+        };
+        let hops = |path: &[(u8, Ipv4Addr)]| {
+            path.iter()
+                .map(|(d, h)| serde_json::json!({ "distance": d, "interface": h.to_string() }))
+                .collect::<Vec<_>>()
+        };
+        let path_changes: Vec<_> = self
+            .path_changes
+            .iter()
+            .map(|c| {
+                serde_json::json!({
+                    "destination": c.destination.to_string(),
+                    "old": hops(&c.old),
+                    "new": hops(&c.new),
+                })
+            })
+            .collect();
+        let rtt_shifts: Vec<_> = self
+            .rtt_shifts
+            .iter()
+            .map(|r| {
+                serde_json::json!({
+                    "interface": r.interface.to_string(),
+                    "old_rtt": r.old_rtt,
+                    "new_rtt": r.new_rtt,
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "added_interfaces": addrs(&self.added_interfaces),
+            "removed_interfaces": addrs(&self.removed_interfaces),
+            "added_links": links(&self.added_links),
+            "removed_links": links(&self.removed_links),
+            "path_changes": path_changes,
+            "rtt_shifts": rtt_shifts,
+        })
+    }
+
+    /// Graphviz source of the union of both topologies, where added parts are green, removed
+    /// ones are red, and interfaces with shifted RTT are orange.
+    pub fn to_dot(&self) -> String {
+        let mut s = String::from("graph {\n    overlap = false;\n");
+        for addr in &self.added_interfaces {
+            let _ = writeln!(s, "    \"{}\" [color = green, fontcolor = green];", addr);
+        }
+        for addr in &self.removed_interfaces {
+            let _ = writeln!(s, "    \"{}\" [color = red, fontcolor = red];", addr);
+        }
+        for r in &self.rtt_shifts {
+            let _ = writeln!(
+                s,
+                "    \"{}\" [color = orange, xlabel = \"{:+} ms\"];",
+                r.interface,
+                r.shift()
+            );
+        }
+        for (a, b) in &self.common_links {
+            let _ = writeln!(s, "    \"{}\" -- \"{}\";", a, b);
+        }
+        for (a, b) in &self.added_links {
+            let _ = writeln!(s, "    \"{}\" -- \"{}\" [color = green];", a, b);
+        }
+        for (a, b) in &self.removed_links {
+            let _ = writeln!(
+                s,
+                "    \"{}\" -- \"{}\" [color = red, style = dashed];",
+                a, b
+            );
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    pub fn print(&self, format: DiffFormat) {
+        match format {
+            DiffFormat::Text => print!("{}", self.to_text()),
+            DiffFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&self.to_json()).unwrap())
+            }
+        }
+    }
+
+    pub async fn render(
+        &self,
+        dot_path: &Path,
+        viz_path: Option<&Path>,
+        layout: &str,
+    ) -> Result<()> {
+        log::info!("Saving topology diff to {}...", dot_path.display());
+        tokio::fs::write(dot_path, self.to_dot()).await?;
+        if let Some(viz_path) = viz_path {
+            Topo::plot(dot_path, viz_path, layout).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prober::ProbeResult;
+
+    #[test]
+    fn test_diff() {
+        let vantage = "10.0.0.1".parse().unwrap();
+        let mut old = Results::new(vantage);
+        old.push(ProbeResult::for_test("1.0.0.1", "10.0.0.254", 1, 1));
+        old.push(ProbeResult::for_test("1.0.0.1", "2.0.0.1", 2, 10));
+        old.push(ProbeResult::for_test("4.0.0.1", "10.0.0.254", 1, 1));
+        let mut new = Results::new(vantage);
+        new.push(ProbeResult::for_test("1.0.0.1", "10.0.0.254", 1, 30));
+        new.push(ProbeResult::for_test("1.0.0.1", "3.0.0.1", 2, 10));
+        // a lost reply is not a path change
+        new.push(ProbeResult::for_test("4.0.0.1", "5.0.0.1", 2, 1));
+
+        let diff = TopoDiff::new(&old, &new, 5);
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        assert_eq!(
+            diff.added_interfaces,
+            vec![addr("3.0.0.1"), addr("5.0.0.1")]
+        );
+        assert_eq!(diff.removed_interfaces, vec![addr("2.0.0.1")]);
+        assert_eq!(
+            diff.added_links,
+            vec![(addr("3.0.0.1"), addr("10.0.0.254"))]
+        );
+        assert_eq!(
+            diff.removed_links,
+            vec![(addr("2.0.0.1"), addr("10.0.0.254"))]
+        );
+        assert_eq!(diff.common_links.len(), 1);
+
+        assert_eq!(diff.path_changes.len(), 1);
+        assert_eq!(diff.path_changes[0].destination, addr("1.0.0.1"));
+
+        assert_eq!(diff.rtt_shifts.len(), 1);
+        assert_eq!(diff.rtt_shifts[0].interface, addr("10.0.0.254"));
+        assert_eq!(diff.rtt_shifts[0].shift(), 29);
+
+        assert!(TopoDiff::new(&old, &old, 5).is_empty());
+    }
+
+    #[test]
+    fn test_path_changed() {
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        let (a, b, c) = (addr("2.0.0.1"), addr("2.0.0.2"), addr("2.0.0.3"));
+        // load balanced, where any of the responders may be kept
+        assert!(!path_changed(&[(2, a), (2, b)], &[(2, b)]));
+        assert!(!path_changed(&[(2, b)], &[(2, a), (2, b)]));
+        assert!(path_changed(&[(2, a), (2, b)], &[(2, c)]));
+        // not observed in both
+        assert!(!path_changed(&[(2, a)], &[(3, c)]));
+    }
+}
@@ -10,16 +10,69 @@ pub enum Error {
     ParseError(u8),
     #[error("unexpected icmp packet with source port `{0}`, expected `{1}`")]
     UnexpectedIcmpSrcPort(u16, u16),
+    #[error("unexpected probe mac `{0}`, expected `{1}`")]
+    UnexpectedProbeMac(u16, u16),
+    #[error("unexpected probe phase `{0:?}`, expected `{1:?}`")]
+    UnexpectedProbePhase(crate::prober::ProbePhase, crate::prober::ProbePhase),
     #[error("invalid distance with initial_ttl `{0}` and dst_ttl `{1}`")]
     InvalidDistance(u8, u8),
     #[error("unexpected icmp packet with type `{0:?}` and code `{1:?}`")]
     UnexpectedIcmpType(pnet::packet::icmp::IcmpType, pnet::packet::icmp::IcmpCode),
     #[error("")]
     BadGrainOrNet(u8, ipnet::Ipv4Net),
-    #[error("")]
+    #[error("invalid target `{0}`")]
     InvalidIpv4Addr(String),
     #[error("")]
     CannotResolveTargets(String),
+    #[error("invalid network `{0}` in address list")]
+    InvalidNetwork(String),
+    #[error("invalid hitlist entry `{0}`")]
+    InvalidHitlistEntry(String),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("invalid result line `{0}`")]
+    InvalidResultLine(String),
+    #[error("invalid capture `{0}`")]
+    InvalidCapture(String),
+    #[error("invalid cluster message `{0}`")]
+    InvalidClusterMessage(String),
+    #[error("rejected by the coordinator: {0}")]
+    RejectedByCoordinator(String),
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error), // thus io::Error can implicitly `into` IoError
 }
+
+impl Error {
+    /// Name of the variant, as a label of metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::NoSuchInterface(..) => "NoSuchInterface",
+            Error::ParseError(..) => "ParseError",
+            Error::UnexpectedIcmpSrcPort(..) => "UnexpectedIcmpSrcPort",
+            Error::UnexpectedProbeMac(..) => "UnexpectedProbeMac",
+            Error::UnexpectedProbePhase(..) => "UnexpectedProbePhase",
+            Error::InvalidDistance(..) => "InvalidDistance",
+            Error::UnexpectedIcmpType(..) => "UnexpectedIcmpType",
+            Error::BadGrainOrNet(..) => "BadGrainOrNet",
+            Error::InvalidIpv4Addr(..) => "InvalidIpv4Addr",
+            Error::CannotResolveTargets(..) => "CannotResolveTargets",
+            Error::InvalidNetwork(..) => "InvalidNetwork",
+            Error::InvalidHitlistEntry(..) => "InvalidHitlistEntry",
+            Error::InvalidConfig(..) => "InvalidConfig",
+            Error::InvalidResultLine(..) => "InvalidResultLine",
+            Error::InvalidCapture(..) => "InvalidCapture",
+            Error::InvalidClusterMessage(..) => "InvalidClusterMessage",
+            Error::RejectedByCoordinator(..) => "RejectedByCoordinator",
+            Error::IoError(..) => "IoError",
+        }
+    }
+
+    /// Where exactly a reply fails to parse, e.g., the stage or the icmp type/code.
+    pub fn detail(&self) -> String {
+        match self {
+            Error::ParseError(stage) => stage.to_string(),
+            Error::UnexpectedIcmpType(t, c) => format!("{}/{}", t.0, c.0),
+            _ => String::new(),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{fmt, net::Ipv4Addr, path::PathBuf};
+
+use ipnet::Ipv4Net;
+use rand::{rngs::StdRng, Rng};
+
+use crate::{error::*, OPT};
+
+/// A set of IPv4 addresses loaded from a ZMap-compatible list file, i.e., one address or
+/// network per line, with `#` starting a comment. Stored as sorted, merged inclusive ranges.
+#[derive(Clone, Default)]
+pub struct AddrSet {
+    path: PathBuf,
+    ranges: Vec<(u32, u32)>,
+}
+
+impl AddrSet {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut set = Self::parse(&content)?;
+        set.path = path.into();
+        Ok(set)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let net = if let Ok(net) = line.parse::<Ipv4Net>() {
+                net
+            } else if let Ok(addr) = line.parse::<Ipv4Addr>() {
+                Ipv4Net::new(addr, 32).unwrap()
+            } else {
+                return Err(Error::InvalidNetwork(line.to_owned()));
+            };
+            ranges.push((net.network().into(), net.broadcast().into()));
+        }
+
+        ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (lo, hi) in ranges {
+            match merged.last_mut() {
+                Some((_, last_hi)) if lo <= last_hi.saturating_add(1) => {
+                    *last_hi = (*last_hi).max(hi);
+                }
+                _ => merged.push((lo, hi)),
+            }
+        }
+
+        Ok(Self {
+            path: PathBuf::new(),
+            ranges: merged,
+        })
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let u: u32 = addr.into();
+        match self.ranges.binary_search_by_key(&u, |&(lo, _)| lo) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => u <= self.ranges[i - 1].1,
+        }
+    }
+
+    pub fn addr_count(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|&(lo, hi)| (hi - lo) as u64 + 1)
+            .sum()
+    }
+}
+
+impl fmt::Debug for AddrSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AddrSet({:?}, {} ranges, {} addrs)",
+            self.path,
+            self.ranges.len(),
+            self.addr_count()
+        )
+    }
+}
+
+/// Check an address against `--allowlist` and `--blocklist`.
+pub fn is_permitted(addr: Ipv4Addr) -> bool {
+    if let Some(allowlist) = &OPT.allowlist {
+        if !allowlist.contains(addr) {
+            return false;
+        }
+    }
+    if let Some(blocklist) = &OPT.blocklist {
+        if blocklist.contains(addr) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Times to pick a host in a block before leaving it to be filtered out.
+const PICK_ATTEMPTS: usize = 16;
+
+/// Pick a random host in `[low, high)`, re-picking while it is not permitted, so that a block
+/// partially covered by `--allowlist` or `--blocklist` is still probed. The last pick is returned
+/// if none is permitted after `PICK_ATTEMPTS`.
+pub fn pick_permitted(low: u64, high: u64, rng: &mut StdRng) -> Ipv4Addr {
+    pick_where(low, high, rng, is_permitted)
+}
+
+fn pick_where(
+    low: u64,
+    high: u64,
+    rng: &mut StdRng,
+    permitted: impl Fn(Ipv4Addr) -> bool,
+) -> Ipv4Addr {
+    let mut addr = Ipv4Addr::from(rng.gen_range(low, high) as u32);
+    for _ in 1..PICK_ATTEMPTS {
+        if permitted(addr) {
+            break;
+        }
+        addr = Ipv4Addr::from(rng.gen_range(low, high) as u32);
+    }
+    addr
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_contains() {
+        let set = AddrSet::parse(
+            "# opt-out list\n\
+             10.0.0.0/8\n\
+             \n\
+             192.168.1.1   # single host\n\
+             10.1.0.0/16\n\
+             11.0.0.0/8\n",
+        )
+        .unwrap();
+        assert_eq!(set.ranges.len(), 2);
+        assert_eq!(set.addr_count(), (2 << 24) + 1);
+        assert!(set.contains("10.0.0.0".parse().unwrap()));
+        assert!(set.contains("11.255.255.255".parse().unwrap()));
+        assert!(set.contains("192.168.1.1".parse().unwrap()));
+        assert!(!set.contains("192.168.1.2".parse().unwrap()));
+        assert!(!set.contains("9.255.255.255".parse().unwrap()));
+        assert!(!set.contains("12.0.0.0".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(AddrSet::parse("10.0.0.0/33").is_err());
+        assert!(AddrSet::parse("not an address").is_err());
+    }
+
+    #[test]
+    fn test_pick_where() {
+        use rand::SeedableRng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let blocked = AddrSet::parse("10.0.0.0/25").unwrap();
+        let block: Ipv4Net = "10.0.0.0/24".parse().unwrap();
+        let (low, high) = (
+            u32::from(block.network()) as u64,
+            u32::from(block.broadcast()) as u64 + 1,
+        );
+        for _ in 0..100 {
+            let addr = pick_where(low, high, &mut rng, |a| !blocked.contains(a));
+            assert!(block.contains(&addr) && !blocked.contains(addr));
+        }
+        // none permitted, left to be filtered out
+        let addr = pick_where(low, high, &mut rng, |_| false);
+        assert!(block.contains(&addr));
+    }
+}
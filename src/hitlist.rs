@@ -0,0 +1,115 @@
+use std::{net::Ipv4Addr, path::Path};
+
+use hashbrown::hash_map::HashMap;
+
+use crate::error::*;
+
+/// Most responsive known host of each grain-sized block, loaded from an ISI IPv4 hitlist.
+///
+/// Each line of the hitlist contains a responsiveness score and an address, either as dotted
+/// decimal or as 8 hex digits like the ISI datasets do. Lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Hitlist {
+    grain: u8,
+    best: HashMap<u32, (Ipv4Addr, i32)>,
+}
+
+impl Hitlist {
+    /// Hosts with a score not greater than this are considered unresponsive.
+    const MIN_SCORE: i32 = 0;
+
+    pub fn from_file(path: &Path, grain: u8) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content, grain)
+    }
+
+    pub fn parse(content: &str, grain: u8) -> Result<Self> {
+        let mut hitlist = Self {
+            grain,
+            best: HashMap::new(),
+        };
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (addr, score) =
+                Self::parse_line(line).ok_or(Error::InvalidHitlistEntry(line.to_owned()))?;
+            if score <= Self::MIN_SCORE {
+                continue;
+            }
+
+            let block = hitlist.block_of(addr);
+            let entry = hitlist.best.entry(block).or_insert((addr, score));
+            if score > entry.1 {
+                *entry = (addr, score);
+            }
+        }
+
+        Ok(hitlist)
+    }
+
+    fn parse_line(line: &str) -> Option<(Ipv4Addr, i32)> {
+        let mut fields = line.split_whitespace();
+        let (a, b) = (fields.next()?, fields.next()?);
+        if a.contains('.') {
+            Some((a.parse().ok()?, b.parse().ok()?))
+        } else if b.contains('.') {
+            Some((b.parse().ok()?, a.parse().ok()?))
+        } else {
+            // ISI order: score, then hex address
+            let addr = u32::from_str_radix(b, 16).ok()?;
+            Some((addr.into(), a.parse().ok()?))
+        }
+    }
+
+    fn block_of(&self, addr: Ipv4Addr) -> u32 {
+        let u: u32 = addr.into();
+        u.checked_shr(self.grain as u32).unwrap_or(0)
+    }
+
+    /// The most responsive host in the grain-sized block containing `addr`, if any.
+    pub fn best_in_block(&self, addr: Ipv4Addr) -> Option<Ipv4Addr> {
+        self.best.get(&self.block_of(addr)).map(|&(addr, _)| addr)
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.best.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let hitlist = Hitlist::parse(
+            "# score\tip\n\
+             10\t0a000001\n\
+             99\t0a000002\n\
+             -99\t0a000003\n\
+             1.2.3.4 50\n\
+             -10 1.2.4.4\n",
+            8,
+        )
+        .unwrap();
+        assert_eq!(hitlist.block_count(), 2);
+        assert_eq!(
+            hitlist.best_in_block("10.0.0.200".parse().unwrap()),
+            Some("10.0.0.2".parse().unwrap())
+        );
+        assert_eq!(
+            hitlist.best_in_block("1.2.3.0".parse().unwrap()),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(hitlist.best_in_block("1.2.4.0".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Hitlist::parse("10 zzzz", 8).is_err());
+        assert!(Hitlist::parse("10", 8).is_err());
+    }
+}
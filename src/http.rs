@@ -0,0 +1,206 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use hashbrown::HashMap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{control::CONTROL, error::*, metrics::METRICS, tracerouter::Tracerouter};
+
+/// A minimal HTTP request, where only the request line matters.
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Self> {
+        let mut parts = head.lines().next()?.split_whitespace();
+        let method = parts.next()?.to_owned();
+        let target = parts.next()?;
+        let (path, query) = match target.find('?') {
+            Some(i) => (&target[..i], &target[i + 1..]),
+            None => (target, ""),
+        };
+        let query = query
+            .split('&')
+            .filter(|kv| !kv.is_empty())
+            .map(|kv| match kv.find('=') {
+                Some(i) => (kv[..i].to_owned(), kv[i + 1..].to_owned()),
+                None => (kv.to_owned(), String::new()),
+            })
+            .collect();
+
+        Some(Self {
+            method,
+            path: path.to_owned(),
+            query,
+        })
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, serde_json::json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+fn status(tr: &Tracerouter) -> serde_json::Value {
+    let mut status = CONTROL.to_json();
+    status["stopped"] = tr.stopped().into();
+    status
+}
+
+fn route(req: &Request, tr: &Tracerouter) -> Response {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/status") => Response::json(200, status(tr)),
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: METRICS.render(),
+        },
+        ("POST", "/pause") => {
+            CONTROL.set_paused(true);
+            Response::json(200, status(tr))
+        }
+        ("POST", "/resume") => {
+            CONTROL.set_paused(false);
+            Response::json(200, status(tr))
+        }
+        ("POST", "/stop") => {
+            log::warn!("Stopping by the control API...");
+            tr.stop();
+            Response::json(200, status(tr))
+        }
+        ("POST", "/rate") => match req.query.get("pps").and_then(|r| r.parse().ok()) {
+            Some(rate) => {
+                CONTROL.set_probing_rate(rate);
+                Response::json(200, status(tr))
+            }
+            None => Response::error(400, "expected `pps=<probes per second>`"),
+        },
+        (_, "/status")
+        | (_, "/metrics")
+        | (_, "/pause")
+        | (_, "/resume")
+        | (_, "/stop")
+        | (_, "/rate") => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+async fn handle(mut stream: TcpStream, tr: &Tracerouter) -> Result<()> {
+    const MAX_HEAD_LENGTH: usize = 8192;
+
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_HEAD_LENGTH {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let response = match Request::parse(&String::from_utf8_lossy(&buf)) {
+        Some(req) => {
+            log::debug!("[HTTP] {} {}", req.method, req.path);
+            route(&req, tr)
+        }
+        None => Response::error(400, "bad request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    Ok(())
+}
+
+/// Serve the control and status API on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr, tr: Arc<Tracerouter>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Control API listening on http://{}", addr);
+    if !addr.ip().is_loopback() {
+        log::warn!(
+            "Control API on {} is not authenticated, anyone reaching it can stop or throttle the scan",
+            addr
+        );
+    }
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let tr = tr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &tr).await {
+                log::debug!("[HTTP] error occurred while handling: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let req =
+            Request::parse("POST /rate?pps=1000&x HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/rate");
+        assert_eq!(req.query["pps"], "1000");
+        assert_eq!(req.query["x"], "");
+        assert!(Request::parse("").is_none());
+    }
+
+    #[test]
+    fn test_route() {
+        let tr = Tracerouter::default();
+        let get = |method: &str, target: &str| {
+            let req = Request::parse(&format!("{} {} HTTP/1.1", method, target)).unwrap();
+            route(&req, &tr).status
+        };
+        assert_eq!(get("GET", "/status"), 200);
+        assert_eq!(get("GET", "/metrics"), 200);
+        assert_eq!(get("GET", "/pause"), 405);
+        assert_eq!(get("POST", "/rate?pps=abc"), 400);
+        assert_eq!(get("GET", "/nowhere"), 404);
+
+        assert_eq!(get("POST", "/stop"), 200);
+        assert!(tr.stopped());
+    }
+}
@@ -3,42 +3,72 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod analyze;
+mod cluster;
+mod config;
+mod control;
 mod dcb;
+mod diff;
 mod error;
+mod filter;
+mod hitlist;
+mod http;
+mod metrics;
+mod monitor;
 mod network;
 mod opt;
+mod pcap;
+mod permutation;
+mod prefix;
 mod prober;
+mod results;
+mod target_list;
 mod topo;
 mod tracerouter;
+mod tui;
 mod utils;
 
 use std::sync::Arc;
 
 use error::Result;
-use opt::Opt;
+use opt::{Command, Opt};
+use results::Results;
 use topo::Topo;
 use tracerouter::Tracerouter;
 
 lazy_static! {
-    static ref OPT: Opt = if cfg!(test) {
-        opt::get_test_opt()
+    static ref CMD: Command = if cfg!(test) {
+        Command::Scan(Box::new(opt::get_test_opt()))
     } else {
-        opt::get_opt()
+        opt::get_command()
+    };
+    static ref OPT: &'static Opt = match &*CMD {
+        Command::Scan(opt) => opt,
+        _ => panic!("options of scanning are only available in `scan`"),
     };
 }
 
-fn init() {
-    env_logger::builder()
-        .filter_level(if OPT.debug {
+fn init_logger(debug: bool) {
+    let logger = env_logger::builder()
+        .filter_level(if debug {
             log::LevelFilter::Debug
         } else {
             log::LevelFilter::Info
         })
         .parse_default_env()
-        .init();
+        .build();
+    let max_level = logger.filter();
+    log::set_boxed_logger(Box::new(tui::Logger::new(logger))).unwrap();
+    log::set_max_level(max_level);
+}
+
+fn init() {
+    init_logger(OPT.debug);
 
     #[cfg(unix)]
-    utils::ensure_su();
+    if OPT.replay.is_none() {
+        utils::ensure_su();
+    }
 
     log::info!("{:?}", *OPT);
 
@@ -51,17 +81,83 @@ fn init() {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    match &*CMD {
+        Command::Scan(_) => scan().await?,
+        Command::Render(opt) => {
+            init_logger(false);
+            let results = Results::read(&opt.input)?;
+            let comment = format!("rendered from {}", opt.input.display());
+            Topo::process_graph(&results, &opt.output, false, &comment).await?;
+        }
+        Command::Analyze(opt) => {
+            let results = Results::read(&opt.input)?;
+            analyze::Summary::new(&results, opt.top).print();
+        }
+        Command::Coordinate(opt) => {
+            init_logger(false);
+            cluster::coordinate(opt).await?;
+        }
+        Command::Diff(opt) => {
+            let old = Results::read(&opt.old)?;
+            let new = Results::read(&opt.new)?;
+            let diff = diff::TopoDiff::new(&old, &new, opt.rtt_threshold);
+            diff.print(opt.format);
+            if let Some(dot_path) = &opt.output_dot {
+                init_logger(false);
+                diff.render(dot_path, opt.output_viz.as_deref(), &opt.layout)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn scan() -> Result<()> {
     init();
+    let _capture = pcap::CaptureGuard;
 
-    let tr = Arc::new(Tracerouter::new()?);
+    let mut tr = match &OPT.coordinator {
+        Some(spec) => Tracerouter::join(spec).await?,
+        None => Tracerouter::new()?,
+    };
+    if OPT.interval.is_some() {
+        tr = tr.with_results_path(Some(monitor::cycle_path(1, "results")));
+    }
+    let tr = Arc::new(tr);
     let running = tr.clone();
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.unwrap();
         running.stop();
     });
+    if let Some(addr) = OPT.http_addr {
+        let running = tr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(addr, running).await {
+                log::error!("control API is down: {}", e);
+            }
+        });
+    }
+
+    let tui = tui::Tui::start();
+    if OPT.interval.is_some() {
+        return monitor::run(tr).await;
+    }
 
-    let topo = tr.run().await?;
-    Topo::process_graph(topo).await?;
+    let results = match &OPT.replay {
+        Some(path) => tr.replay(path).await?,
+        None => tr.run().await?,
+    };
+    drop(tui);
+    if OPT.manifest {
+        log::info!("Saving manifest to {}...", OPT.output_manifest.display());
+        let content = serde_json::to_string_pretty(&tr.manifest()).unwrap();
+        std::fs::write(&OPT.output_manifest, content)?;
+    }
+    if !OPT.scan_only {
+        let comment = OPT.effective_args.join(" ");
+        Topo::process_graph(&results, &OPT.output, tr.partial(), &comment).await?;
+    }
 
     #[cfg(windows)]
     std::process::exit(0);
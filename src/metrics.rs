@@ -0,0 +1,341 @@
+use std::{
+    fmt::Write,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Mutex,
+    },
+};
+
+use hashbrown::HashMap;
+
+use crate::{control::CONTROL, error::Error, prober::ProbePhase};
+
+lazy_static! {
+    /// Counters and gauges of scan health, exported in the Prometheus text format.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Packet counters of a phase, which are the only ones kept; the network manager and the control
+/// API report the difference from a snapshot taken when they began.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct PacketCounters {
+    pub sent: u64,
+    pub recv: u64,
+    pub refused: u64,
+    pub scheduled: u64,
+}
+
+impl PacketCounters {
+    pub fn since(self, base: Self) -> Self {
+        Self {
+            sent: self.sent.saturating_sub(base.sent),
+            recv: self.recv.saturating_sub(base.recv),
+            refused: self.refused.saturating_sub(base.refused),
+            scheduled: self.scheduled.saturating_sub(base.scheduled),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // by `ProbePhase`
+    sent_packets: [AtomicU64; 2],
+    recv_packets: [AtomicU64; 2],
+    scheduled_packets: [AtomicU64; 2],
+    refused_packets: [AtomicU64; 2],
+    // by (variant, detail) of `Error`
+    recv_errors: Mutex<HashMap<(&'static str, String), u64>>,
+    // replies by responder in the main phase, only counted for the TUI
+    responders: Mutex<Responders>,
+
+    pub forward_interfaces: AtomicU64,
+    pub backward_interfaces: AtomicU64,
+    // union of both, as counted in the summary
+    pub interfaces: AtomicU64,
+}
+
+/// Replies by responder, with the ones of most replies kept in order as they are counted.
+#[derive(Debug, Default)]
+struct Responders {
+    counts: HashMap<Ipv4Addr, u64>,
+    // at most `TOP_RESPONDERS`, in descending order of counts
+    top: Vec<(Ipv4Addr, u64)>,
+}
+
+impl Responders {
+    const TOP_RESPONDERS: usize = 16;
+
+    fn count(&mut self, responder: Ipv4Addr) {
+        let count = self.counts.entry(responder).or_insert(0);
+        *count += 1;
+        let count = *count;
+
+        // others never have more replies than the last of `top`, as counts only grow
+        let at = match self.top.iter().position(|&(r, _)| r == responder) {
+            Some(at) => at,
+            None if self.top.len() < Self::TOP_RESPONDERS => {
+                self.top.push((responder, count));
+                self.top.len() - 1
+            }
+            None if self.top.last().unwrap().1 < count => {
+                *self.top.last_mut().unwrap() = (responder, count);
+                self.top.len() - 1
+            }
+            None => return,
+        };
+        self.top[at].1 = count;
+        let mut at = at;
+        while at > 0 && self.top[at - 1].1 < count {
+            self.top.swap(at - 1, at);
+            at -= 1;
+        }
+    }
+}
+
+impl Metrics {
+    pub fn on_scheduled(&self, phase: ProbePhase) {
+        self.scheduled_packets[phase as usize].fetch_add(1, SeqCst);
+    }
+
+    pub fn on_refused(&self, phase: ProbePhase) {
+        self.refused_packets[phase as usize].fetch_add(1, SeqCst);
+    }
+
+    pub fn on_sent(&self, phase: ProbePhase) {
+        self.sent_packets[phase as usize].fetch_add(1, SeqCst);
+    }
+
+    pub fn on_recv(&self, phase: ProbePhase) {
+        self.recv_packets[phase as usize].fetch_add(1, SeqCst);
+    }
+
+    pub fn on_recv_error(&self, error: &Error) {
+        *self
+            .recv_errors
+            .lock()
+            .unwrap()
+            .entry((error.name(), error.detail()))
+            .or_insert(0) += 1;
+    }
+
+    /// Count of discarded replies by (variant, detail) of `Error`, in order.
+    pub fn recv_errors(&self) -> Vec<((&'static str, String), u64)> {
+        let mut errors: Vec<_> = self
+            .recv_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, &v)| (k.clone(), v))
+            .collect();
+        errors.sort_unstable();
+        errors
+    }
+
+    pub fn on_responder(&self, responder: Ipv4Addr) {
+        self.responders.lock().unwrap().count(responder);
+    }
+
+    /// The `n` responders with the most replies, in descending order, where `n` is at most 16.
+    pub fn top_responders(&self, n: usize) -> Vec<(Ipv4Addr, u64)> {
+        let responders = self.responders.lock().unwrap();
+        responders.top.iter().take(n).cloned().collect()
+    }
+
+    /// Reset the interface gauges for a new main phase.
+    pub fn reset_interfaces(&self) {
+        self.forward_interfaces.store(0, SeqCst);
+        self.backward_interfaces.store(0, SeqCst);
+        self.interfaces.store(0, SeqCst);
+    }
+
+    pub fn counters(&self, phase: ProbePhase) -> PacketCounters {
+        let i = phase as usize;
+        PacketCounters {
+            sent: self.sent_packets[i].load(SeqCst),
+            recv: self.recv_packets[i].load(SeqCst),
+            refused: self.refused_packets[i].load(SeqCst),
+            scheduled: self.scheduled_packets[i].load(SeqCst),
+        }
+    }
+
+    /// Probes waiting in the sending queue.
+    pub fn queue_depth(&self) -> u64 {
+        [ProbePhase::Pre, ProbePhase::Main]
+            .iter()
+            .map(|&phase| {
+                let c = self.counters(phase);
+                c.scheduled.saturating_sub(c.sent + c.refused)
+            })
+            .sum()
+    }
+
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        macro_rules! metric {
+            ($name:expr, $kind:expr, $help:expr) => {
+                let _ = writeln!(s, "# HELP flashroute_{} {}", $name, $help);
+                let _ = writeln!(s, "# TYPE flashroute_{} {}", $name, $kind);
+            };
+        }
+
+        let phases = [("pre", ProbePhase::Pre), ("main", ProbePhase::Main)];
+        metric!("probes_sent_total", "counter", "Probes sent.");
+        for (name, phase) in &phases {
+            let count = self.sent_packets[*phase as usize].load(SeqCst);
+            let _ = writeln!(
+                s,
+                "flashroute_probes_sent_total{{phase=\"{}\"}} {}",
+                name, count
+            );
+        }
+        metric!(
+            "replies_received_total",
+            "counter",
+            "Valid replies received."
+        );
+        for (name, phase) in &phases {
+            let count = self.recv_packets[*phase as usize].load(SeqCst);
+            let _ = writeln!(
+                s,
+                "flashroute_replies_received_total{{phase=\"{}\"}} {}",
+                name, count
+            );
+        }
+        metric!(
+            "probes_refused_total",
+            "counter",
+            "Probes refused by opt-out lists."
+        );
+        let refused: u64 = self.refused_packets.iter().map(|c| c.load(SeqCst)).sum();
+        let _ = writeln!(s, "flashroute_probes_refused_total {}", refused);
+        metric!(
+            "recv_errors_total",
+            "counter",
+            "Received packets discarded, by error variant and detail."
+        );
+        for ((variant, detail), count) in self.recv_errors() {
+            let _ = writeln!(
+                s,
+                "flashroute_recv_errors_total{{variant=\"{}\",detail=\"{}\"}} {}",
+                variant, detail, count
+            );
+        }
+
+        metric!("sending_rate", "gauge", "Probes sent in the last second.");
+        let _ = writeln!(s, "flashroute_sending_rate {}", CONTROL.sending_rate.rate());
+        metric!(
+            "probing_rate",
+            "gauge",
+            "Target probes per second, 0 for unlimited."
+        );
+        let rate = match CONTROL.probing_rate() {
+            u64::MAX => 0,
+            rate => rate,
+        };
+        let _ = writeln!(s, "flashroute_probing_rate {}", rate);
+        metric!(
+            "send_queue_depth",
+            "gauge",
+            "Probes waiting in the sending queue."
+        );
+        let _ = writeln!(s, "flashroute_send_queue_depth {}", self.queue_depth());
+        metric!(
+            "remaining_targets",
+            "gauge",
+            "Targets not completed in the current phase."
+        );
+        let _ = writeln!(
+            s,
+            "flashroute_remaining_targets {}",
+            CONTROL.remaining_keys()
+        );
+        metric!(
+            "interfaces",
+            "gauge",
+            "Interfaces discovered in the main phase."
+        );
+        let _ = writeln!(
+            s,
+            "flashroute_interfaces{{direction=\"forward\"}} {}",
+            self.forward_interfaces.load(SeqCst)
+        );
+        let _ = writeln!(
+            s,
+            "flashroute_interfaces{{direction=\"backward\"}} {}",
+            self.backward_interfaces.load(SeqCst)
+        );
+        let _ = writeln!(
+            s,
+            "flashroute_interfaces{{direction=\"all\"}} {}",
+            self.interfaces.load(SeqCst)
+        );
+
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.on_scheduled(ProbePhase::Main);
+        metrics.on_scheduled(ProbePhase::Main);
+        metrics.on_sent(ProbePhase::Main);
+        metrics.on_recv_error(&Error::ParseError(3));
+        metrics.on_recv_error(&Error::ParseError(3));
+        metrics.on_recv_error(&Error::ParseError(4));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("flashroute_probes_sent_total{phase=\"main\"} 1\n"));
+        assert!(rendered
+            .contains("flashroute_recv_errors_total{variant=\"ParseError\",detail=\"3\"} 2\n"));
+        assert!(rendered
+            .contains("flashroute_recv_errors_total{variant=\"ParseError\",detail=\"4\"} 1\n"));
+        assert!(rendered.contains("flashroute_send_queue_depth 1\n"));
+    }
+
+    #[test]
+    fn test_counters() {
+        let metrics = Metrics::default();
+        metrics.on_sent(ProbePhase::Pre);
+        let base = metrics.counters(ProbePhase::Main);
+        metrics.on_scheduled(ProbePhase::Main);
+        metrics.on_sent(ProbePhase::Main);
+        metrics.on_recv(ProbePhase::Main);
+        assert_eq!(
+            metrics.counters(ProbePhase::Main).since(base),
+            PacketCounters {
+                sent: 1,
+                recv: 1,
+                refused: 0,
+                scheduled: 1,
+            }
+        );
+        assert_eq!(metrics.counters(ProbePhase::Pre).sent, 1);
+    }
+
+    #[test]
+    fn test_top_responders() {
+        let metrics = Metrics::default();
+        let addr = |i: u8| Ipv4Addr::new(10, 0, 0, i);
+        // 1 reply from each of 20 responders, then 30, 29, ... more from the last ones
+        for i in 0..20 {
+            metrics.on_responder(addr(i));
+        }
+        for i in 10..20 {
+            for _ in 0..(20 + i) {
+                metrics.on_responder(addr(i));
+            }
+        }
+        let top = metrics.top_responders(3);
+        assert_eq!(top, vec![(addr(19), 40), (addr(18), 39), (addr(17), 38)]);
+        assert_eq!(
+            metrics.top_responders(100).len(),
+            Responders::TOP_RESPONDERS
+        );
+    }
+}
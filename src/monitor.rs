@@ -0,0 +1,228 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::Ipv4Addr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::{diff::TopoDiff, error::*, results::Results, tracerouter::Tracerouter, utils, OPT};
+
+/// Destination of change events, one JSON object per line.
+pub enum EventSink {
+    File(File),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl EventSink {
+    pub fn open(spec: &str) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = spec.strip_prefix("unix:") {
+                return Ok(Self::Unix(UnixStream::connect(path)?));
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(spec)?;
+        Ok(Self::File(file))
+    }
+
+    pub fn emit(&mut self, event: &serde_json::Value) -> Result<()> {
+        let line = format!("{}\n", event);
+        match self {
+            Self::File(file) => file.write_all(line.as_bytes())?,
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write_all(line.as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+/// Change events between the results of two consecutive cycles.
+pub fn change_events(cycle: u64, diff: &TopoDiff) -> Vec<serde_json::Value> {
+    let time_ms = utils::timestamp_ms();
+    let link = |(a, b): &(Ipv4Addr, Ipv4Addr)| vec![a.to_string(), b.to_string()];
+    let hops =
+        |path: &[(u8, Ipv4Addr)]| path.iter().map(|(_, h)| h.to_string()).collect::<Vec<_>>();
+
+    let mut events = Vec::new();
+    for c in &diff.path_changes {
+        events.push(serde_json::json!({
+            "event": "path_change",
+            "cycle": cycle,
+            "time_ms": time_ms,
+            "destination": c.destination.to_string(),
+            "old": hops(&c.old),
+            "new": hops(&c.new),
+        }));
+    }
+    for l in &diff.added_links {
+        events.push(serde_json::json!({
+            "event": "new_link",
+            "cycle": cycle,
+            "time_ms": time_ms,
+            "link": link(l),
+        }));
+    }
+    for l in &diff.removed_links {
+        events.push(serde_json::json!({
+            "event": "removed_link",
+            "cycle": cycle,
+            "time_ms": time_ms,
+            "link": link(l),
+        }));
+    }
+    events
+}
+
+/// Emit events to the sink, reconnecting once if it fails, e.g., when the consumer behind a Unix
+/// socket restarted. Otherwise the events of this cycle are dropped, and the sink is reconnected
+/// in the next cycle.
+fn emit_events(sink: &mut Option<EventSink>, spec: &str, events: &[serde_json::Value]) {
+    let mut reconnected = false;
+    for (i, event) in events.iter().enumerate() {
+        loop {
+            if sink.is_none() && !reconnected {
+                reconnected = true;
+                match EventSink::open(spec) {
+                    Ok(s) => *sink = Some(s),
+                    Err(e) => log::warn!("[Monitor] Failed to reconnect to {}: {}", spec, e),
+                }
+            }
+            let s = match sink.as_mut() {
+                Some(s) => s,
+                None => {
+                    log::warn!("[Monitor] Dropped {} events", events.len() - i);
+                    return;
+                }
+            };
+            match s.emit(event) {
+                Ok(()) => break,
+                Err(e) => {
+                    log::warn!("[Monitor] Failed to emit events to {}: {}", spec, e);
+                    *sink = None;
+                }
+            }
+        }
+    }
+}
+
+pub fn cycle_path(cycle: u64, extension: &str) -> PathBuf {
+    OPT.cycle_dir.join(format!("cycle-{}.{}", cycle, extension))
+}
+
+/// Scan the same targets every `--interval` seconds until stopped or `--cycles` are done. Each
+/// cycle but the first skips preprobing, and is compared with the previous one for events.
+pub async fn run(first: Arc<Tracerouter>) -> Result<()> {
+    let interval = Duration::from_secs(OPT.interval.unwrap());
+    std::fs::create_dir_all(&OPT.cycle_dir)?;
+    let mut sink = match &OPT.events {
+        Some(spec) => Some(EventSink::open(spec)?),
+        None => None,
+    };
+
+    let mut tr = first;
+    let mut previous: Option<Results> = None;
+    let mut cycle = 1u64;
+    loop {
+        let start_time = SystemTime::now();
+        log::info!("[Monitor] Cycle {} begins", cycle);
+        tr.run().await?;
+        if tr.partial() {
+            log::warn!("[Monitor] Cycle {} is partial, thus not compared", cycle);
+        } else {
+            let results = Results::read(&cycle_path(cycle, "results"))?;
+            if let Some(previous) = &previous {
+                // RTT shifts are too noisy to be events
+                let diff = TopoDiff::new(previous, &results, u16::MAX);
+                let events = change_events(cycle, &diff);
+                log::info!("[Monitor] Cycle {}: {} change events", cycle, events.len());
+                if let Some(spec) = &OPT.events {
+                    emit_events(&mut sink, spec, &events);
+                }
+            }
+            previous = Some(results);
+        }
+        if OPT.manifest {
+            let mut manifest = tr.manifest();
+            manifest["cycle"] = cycle.into();
+            let content = serde_json::to_string_pretty(&manifest).unwrap();
+            std::fs::write(cycle_path(cycle, "json"), content)?;
+        }
+
+        if tr.stopped() || Some(cycle) == OPT.cycles {
+            break;
+        }
+        while !tr.stopped() {
+            let elapsed = SystemTime::now().duration_since(start_time).unwrap();
+            if elapsed >= interval {
+                break;
+            }
+            tokio::time::sleep((interval - elapsed).min(Duration::from_millis(100))).await;
+        }
+        if tr.stopped() {
+            break;
+        }
+
+        cycle += 1;
+        tr = Arc::new(tr.next_cycle(Some(cycle_path(cycle, "results"))));
+    }
+
+    log::info!("[Monitor] Stopped after {} cycles", cycle);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_change_events() {
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        let diff = TopoDiff {
+            added_links: vec![(addr("1.0.0.1"), addr("2.0.0.1"))],
+            ..TopoDiff::default()
+        };
+        let events = change_events(2, &diff);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "new_link");
+        assert_eq!(events[0]["cycle"], 2);
+        assert_eq!(events[0]["link"][1], "2.0.0.1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_emit_events_reconnect() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixListener;
+
+        let path = crate::utils::test_path("events.sock");
+        let spec = format!("unix:{}", path.display());
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut sink = Some(EventSink::open(&spec).unwrap());
+        // the consumer restarts
+        drop(listener.accept().unwrap());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let events = vec![serde_json::json!({ "cycle": 2 })];
+        emit_events(&mut sink, &spec, &events);
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"cycle\":2}\n");
+
+        // events are dropped without any consumer
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        emit_events(&mut sink, &spec, &events);
+        emit_events(&mut sink, &spec, &events);
+        assert!(sink.is_none());
+    }
+}
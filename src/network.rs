@@ -1,5 +1,5 @@
 use std::{
-    net::IpAddr,
+    net::{IpAddr, Ipv4Addr},
     sync::atomic::Ordering,
     sync::{
         atomic::{AtomicBool, AtomicU64},
@@ -10,9 +10,14 @@ use std::{
 };
 
 use crate::{
+    control::CONTROL,
     error::*,
-    prober::{ProbeResult, ProbeUnit, Prober},
-    OPT,
+    filter,
+    metrics::{PacketCounters, METRICS},
+    opt::SourceSelect,
+    pcap,
+    prober::{ProbePhase, ProbeResult, ProbeUnit, Prober},
+    utils, OPT,
 };
 use pnet::{
     packet::{
@@ -34,38 +39,107 @@ type BMpscRx<T> = mpsc::Receiver<T>;
 type OneshotTx<T> = oneshot::Sender<T>;
 type OneshotRx<T> = oneshot::Receiver<T>;
 
+/// Timing of replies, for deciding how long to wait for the in-flight ones.
+#[derive(Debug)]
+pub struct ReplyTiming {
+    last_recv_ms: AtomicU64,
+    // 1 ms per bucket, the last one for all greater
+    rtt_buckets: Vec<AtomicU64>,
+}
+
+impl ReplyTiming {
+    const MAX_RTT_MS: usize = 10_000;
+
+    pub fn new() -> Self {
+        Self {
+            last_recv_ms: AtomicU64::new(0),
+            rtt_buckets: (0..=Self::MAX_RTT_MS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, result: &ProbeResult) {
+        self.last_recv_ms.store(utils::timestamp_ms(), SeqCst);
+        if OPT.encode_timestamp {
+            let bucket = (result.debug.rtt as usize).min(Self::MAX_RTT_MS);
+            self.rtt_buckets[bucket].fetch_add(1, SeqCst);
+        }
+    }
+
+    pub fn last_recv_ms(&self) -> u64 {
+        self.last_recv_ms.load(SeqCst)
+    }
+
+    /// The `q`-quantile of measured RTTs, or `None` if nothing is measured.
+    pub fn rtt_quantile(&self, q: f64) -> Option<Duration> {
+        let counts: Vec<u64> = self.rtt_buckets.iter().map(|c| c.load(SeqCst)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = ((total as f64 * q).ceil() as u64).max(1);
+        let mut acc = 0u64;
+        for (ms, count) in counts.into_iter().enumerate() {
+            acc += count;
+            if acc >= target {
+                return Some(Duration::from_millis(ms as u64));
+            }
+        }
+        Some(Duration::from_millis(Self::MAX_RTT_MS as u64))
+    }
+}
+
+/// Index of the source address of the `sequence`-th probe to `destination` among `count` ones.
+fn select_source(
+    select: SourceSelect,
+    count: usize,
+    destination: Ipv4Addr,
+    sequence: u64,
+) -> usize {
+    match select {
+        SourceSelect::Hash => utils::ip_checksum(destination, OPT.salt) as usize % count,
+        SourceSelect::RoundRobin => (sequence % count as u64) as usize,
+    }
+}
+
 pub struct NetworkManager {
-    sent_packets: Arc<AtomicU64>,
-    recv_packets: Arc<AtomicU64>,
+    phase: ProbePhase,
+    // `METRICS` counters when this manager was created
+    base: PacketCounters,
+    reply_timing: Arc<ReplyTiming>,
     send_tx: BMpscTx<ProbeUnit>,
     stopped: Arc<AtomicBool>,
     stop_txs: Vec<OneshotTx<()>>,
 }
 
 impl NetworkManager {
+    const MIN_IDLE_MS: u64 = 100;
+    const WAIT_STEP_MS: u64 = 20;
+
     pub fn new(prober: Prober, recv_tx: MpscTx<ProbeResult>) -> Result<Self> {
         let (send_tx, send_rx) = mpsc::channel(OPT.probing_rate.min(400_000).max(1_000) as usize);
 
+        let phase = prober.phase;
+        let base = METRICS.counters(phase);
         let prober = Arc::new(prober);
-        let sent_packets = Arc::new(AtomicU64::new(0));
-        let recv_packets = Arc::new(AtomicU64::new(0));
+        let reply_timing = Arc::new(ReplyTiming::new());
         let mut stop_txs = Vec::new();
 
         let (stop_tx, stop_rx) = oneshot::channel::<()>();
         stop_txs.push(stop_tx);
-        Self::start_sending_task(prober.clone(), send_rx, stop_rx, sent_packets.clone())?;
+        Self::start_sending_task(prober.clone(), send_rx, stop_rx)?;
 
         let stopped = Arc::new(AtomicBool::new(false));
         Self::start_recving_task(
             prober.clone(),
             stopped.clone(),
-            recv_packets.clone(),
+            reply_timing.clone(),
             recv_tx,
         )?;
 
         Ok(Self {
-            sent_packets,
-            recv_packets,
+            phase,
+            base,
+            reply_timing,
             send_tx,
             stopped,
             stop_txs,
@@ -76,30 +150,36 @@ impl NetworkManager {
         prober: Arc<Prober>,
         mut rx: BMpscRx<ProbeUnit>,
         mut stop_rx: OneshotRx<()>,
-        sent_packets: Arc<AtomicU64>,
     ) -> Result<()> {
-        let protocol = Layer3(Udp);
-        let (mut sender, _) = transport_channel(0, protocol)?;
-        let local_ip = OPT.local_addr;
+        let phase = prober.phase;
 
-        let (net_send_tx, mut net_send_rx) = mpsc::channel::<Ipv4Packet>(10000);
+        // one socket and task per source address, to send in parallel
+        let mut net_send_txs = Vec::with_capacity(OPT.source_addrs.len());
+        for _ in &OPT.source_addrs {
+            let protocol = Layer3(Udp);
+            let (mut sender, _) = transport_channel(0, protocol)?;
+            let (net_send_tx, mut net_send_rx) = mpsc::channel::<Ipv4Packet>(10000);
+            net_send_txs.push(net_send_tx);
 
-        tokio::spawn(async move {
-            loop {
-                if let Some(packet) = net_send_rx.recv().await {
-                    if !OPT.dry_run {
-                        let dst = packet.get_destination();
-                        let _ = sender.send_to(packet, IpAddr::V4(dst));
+            tokio::spawn(async move {
+                loop {
+                    if let Some(packet) = net_send_rx.recv().await {
+                        if !OPT.dry_run {
+                            let dst = packet.get_destination();
+                            pcap::capture(pcap::Direction::Outbound, phase, packet.packet());
+                            let _ = sender.send_to(packet, IpAddr::V4(dst));
+                        }
+                    } else {
+                        break;
                     }
-                } else {
-                    break;
                 }
-            }
-        });
+            });
+        }
 
         tokio::spawn(async move {
             log::info!("[{:?}] sending task started", prober.phase);
 
+            let mut sequence = 0u64;
             let mut sent_this_sec = 0u64;
             let mut last_seen = SystemTime::now();
             let one_sec = Duration::from_secs(1);
@@ -110,6 +190,13 @@ impl NetworkManager {
                         break;
                     }
                     Some(dst_unit) = rx.recv() => {
+                        // Safety net for opt-out lists, targets should have been filtered already
+                        if !filter::is_permitted(dst_unit.0) {
+                            log::warn!("Refused to probe {}, which is not permitted", dst_unit.0);
+                            METRICS.on_refused(prober.phase);
+                            continue;
+                        }
+
                         // Probing rate control
                         if sent_this_sec % 128 == 0 {
                             let now = SystemTime::now();
@@ -118,20 +205,28 @@ impl NetworkManager {
                                 sent_this_sec = 0;
                                 last_seen = now;
                             }
-                            if sent_this_sec > OPT.probing_rate {
+                            if sent_this_sec > CONTROL.probing_rate() {
                                 tokio::time::sleep(one_sec - time_elapsed).await;
                             }
                         }
 
+                        let source = select_source(
+                            OPT.source_select,
+                            OPT.source_addrs.len(),
+                            dst_unit.0,
+                            sequence,
+                        );
+                        sequence += 1;
                         let mut buf = vec![0u8; Prober::PACK_BUFFER_LENGTH];
-                        let len = prober.pack(dst_unit, local_ip, &mut buf);
+                        let len = prober.pack(dst_unit, OPT.source_addrs[source], &mut buf);
                         buf.resize(len, 0);
                         let packet = Ipv4Packet::owned(buf).unwrap();
-                        let _ = net_send_tx.send(packet).await;
+                        let _ = net_send_txs[source].send(packet).await;
 
                         log::trace!("PROBE: {:?}", dst_unit);
 
-                        sent_packets.fetch_add(1, SeqCst);
+                        CONTROL.sending_rate.tick();
+                        METRICS.on_sent(prober.phase);
                         sent_this_sec += 1;
                     }
                 }
@@ -148,7 +243,7 @@ impl NetworkManager {
     fn start_recving_task(
         prober: Arc<Prober>,
         stopped: Arc<AtomicBool>,
-        recv_packets: Arc<AtomicU64>,
+        reply_timing: Arc<ReplyTiming>,
         recv_tx: MpscTx<ProbeResult>,
     ) -> Result<()> {
         let protocol = Layer3(Icmp);
@@ -168,18 +263,15 @@ impl NetworkManager {
                 }
 
                 if let Ok(Some((ip_packet, _addr))) = iter.next_with_timeout(io_timeout) {
+                    pcap::capture(pcap::Direction::Inbound, prober.phase, ip_packet.packet());
                     match prober.parse(ip_packet.packet(), false) {
                         Ok(result) => {
                             log::debug!("[{:?}] RECV: {:?}", prober.phase, result);
+                            reply_timing.record(&result);
                             let _ = recv_tx.send(result);
-                            recv_packets.fetch_add(1, SeqCst);
-                        }
-                        Err(e @ Error::ParseError(_)) => {
-                            log::warn!("error occurred while parsing: {}", e);
-                        }
-                        Err(e) => {
-                            log::debug!("error occurred while parsing: {}", e);
+                            METRICS.on_recv(prober.phase);
                         }
+                        Err(e) => Self::on_parse_error(&e, ip_packet.packet()),
                     }
                 }
             }
@@ -199,19 +291,22 @@ impl NetworkManager {
 
                 loop {
                     match iter.next() {
-                        Ok((ip_packet, _addr)) => match prober.parse(ip_packet.packet(), false) {
-                            Ok(result) => {
-                                log::debug!("[{:?}] RECV: {:?}", prober.phase, result);
-                                let _ = recv_tx.send(result);
-                                recv_packets.fetch_add(1, SeqCst);
-                            }
-                            Err(e @ Error::ParseError(_)) => {
-                                log::warn!("error occurred while parsing: {}", e);
+                        Ok((ip_packet, _addr)) => {
+                            pcap::capture(
+                                pcap::Direction::Inbound,
+                                prober.phase,
+                                ip_packet.packet(),
+                            );
+                            match prober.parse(ip_packet.packet(), false) {
+                                Ok(result) => {
+                                    log::debug!("[{:?}] RECV: {:?}", prober.phase, result);
+                                    reply_timing.record(&result);
+                                    let _ = recv_tx.send(result);
+                                    METRICS.on_recv(prober.phase);
+                                }
+                                Err(e) => Self::on_parse_error(&e, ip_packet.packet()),
                             }
-                            Err(e) => {
-                                log::debug!("error occurred while parsing: {}", e);
-                            }
-                        },
+                        }
                         Err(_) => {
                             break;
                         }
@@ -239,8 +334,73 @@ impl NetworkManager {
         Ok(())
     }
 
+    fn on_parse_error(e: &Error, packet: &[u8]) {
+        if let Error::ParseError(_) = e {
+            log::warn!("error occurred while parsing: {}", e);
+        } else {
+            log::debug!("error occurred while parsing: {}", e);
+        }
+        METRICS.on_recv_error(e);
+        pcap::sample_error(packet);
+    }
+
     pub async fn schedule_probe(&self, unit: ProbeUnit) {
         let _ = self.send_tx.send(unit).await;
+        METRICS.on_scheduled(self.phase);
+    }
+
+    /// Packet counters since this manager was created.
+    fn counters(&self) -> PacketCounters {
+        METRICS.counters(self.phase).since(self.base)
+    }
+
+    /// Whether all scheduled probes have left the sending queue.
+    fn drained(&self) -> bool {
+        let counters = self.counters();
+        counters.sent + counters.refused >= counters.scheduled
+    }
+
+    /// Wait for in-flight replies for at most `max_wait`. With `--adaptive-wait`, return early
+    /// once no reply has arrived for the `--rtt-quantile` of RTTs measured so far.
+    pub async fn wait_for_replies(&self, max_wait: Duration) {
+        let start = utils::timestamp_ms();
+        let max_wait_ms = max_wait.as_millis() as u64;
+        let mut drained_since = None;
+
+        loop {
+            let now = utils::timestamp_ms();
+            if now.saturating_sub(start) >= max_wait_ms {
+                break;
+            }
+
+            if OPT.adaptive_wait {
+                if drained_since.is_none() && self.drained() {
+                    drained_since = Some(now);
+                }
+                if let (Some(drained_since), Some(rtt)) = (
+                    drained_since,
+                    self.reply_timing.rtt_quantile(OPT.rtt_quantile),
+                ) {
+                    let idle =
+                        now.saturating_sub(drained_since.max(self.reply_timing.last_recv_ms()));
+                    let threshold = (rtt.as_millis() as u64).max(Self::MIN_IDLE_MS);
+                    if idle >= threshold {
+                        log::info!(
+                            "No replies for {} ms, stop waiting after {} ms",
+                            idle,
+                            now - start
+                        );
+                        break;
+                    }
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(Self::WAIT_STEP_MS)).await;
+        }
+    }
+
+    pub fn reply_timing(&self) -> &ReplyTiming {
+        &self.reply_timing
     }
 
     pub fn stop(&mut self) {
@@ -251,10 +411,48 @@ impl NetworkManager {
     }
 
     pub fn sent_packets(&self) -> u64 {
-        self.sent_packets.load(SeqCst)
+        self.counters().sent
     }
 
     pub fn recv_packets(&self) -> u64 {
-        self.recv_packets.load(SeqCst)
+        self.counters().recv
+    }
+
+    pub fn refused_packets(&self) -> u64 {
+        self.counters().refused
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rtt_quantile() {
+        let timing = ReplyTiming::new();
+        assert_eq!(timing.rtt_quantile(0.99), None);
+        for ms in 1..=100 {
+            timing.rtt_buckets[ms].fetch_add(1, SeqCst);
+        }
+        timing.rtt_buckets[ReplyTiming::MAX_RTT_MS].fetch_add(1, SeqCst);
+        assert_eq!(timing.rtt_quantile(0.5), Some(Duration::from_millis(51)));
+        assert_eq!(timing.rtt_quantile(0.99), Some(Duration::from_millis(100)));
+        assert_eq!(
+            timing.rtt_quantile(1.0),
+            Some(Duration::from_millis(ReplyTiming::MAX_RTT_MS as u64))
+        );
+    }
+
+    #[test]
+    fn test_select_source() {
+        let dst: Ipv4Addr = "1.2.3.4".parse().unwrap();
+        let hashed: Vec<_> = (0..4)
+            .map(|seq| select_source(SourceSelect::Hash, 3, dst, seq))
+            .collect();
+        assert!(hashed.iter().all(|&i| i == hashed[0] && i < 3));
+        let rr: Vec<_> = (0..4)
+            .map(|seq| select_source(SourceSelect::RoundRobin, 3, dst, seq))
+            .collect();
+        assert_eq!(rr, vec![0, 1, 2, 0]);
     }
 }
@@ -1,17 +1,28 @@
+use serde::{Serialize, Serializer};
 use std::path::PathBuf;
-use structopt::StructOpt;
+use structopt::{
+    clap::{AppSettings, ErrorKind},
+    StructOpt,
+};
 
+use crate::config;
 use crate::error::*;
+use crate::filter::AddrSet;
+use crate::prefix::PrefixMap;
+use crate::prober::ProbeKey;
 use crate::utils;
 
-#[derive(Debug, StructOpt)]
-#[structopt(about)]
+#[derive(Debug, StructOpt, Serialize)]
+#[structopt(about, setting = AppSettings::AllArgsOverrideSelf)]
 pub struct Opt {
     // Preprobing
     #[structopt(long, default_value = "32")]
     pub preprobing_ttl: u8,
     #[structopt(long, default_value = "5")]
     pub proximity_span: u32,
+    #[structopt(long, parse(try_from_str = PrefixMap::from_file))]
+    #[serde(serialize_with = "debug")]
+    pub prefixes: Option<PrefixMap>,
 
     // Probing
     #[structopt(long, default_value = "16")]
@@ -28,58 +39,358 @@ pub struct Opt {
     pub redundancy_removal: bool,
     #[structopt(long = "no-encode-timestamp", parse(from_flag = std::ops::Not::not))]
     pub encode_timestamp: bool,
+    #[structopt(long, default_value = "20")]
+    pub round_ms_per_target: u64,
+    #[structopt(long, default_value = "1000")]
+    pub max_round_ms: u64,
+    #[structopt(long)]
+    pub max_probes: Option<u64>,
+    /// Seconds, including the waits for replies.
+    #[structopt(long)]
+    pub max_duration: Option<u64>,
+
+    // Waiting
+    /// Seconds to wait for replies after preprobing.
+    #[structopt(long, default_value = "3")]
+    pub preprobing_wait: u64,
+    /// Seconds to wait for replies after probing.
+    #[structopt(long, default_value = "5")]
+    pub probing_wait: u64,
+    /// Stop waiting once no replies arrive for an RTT quantile, also used as round duration.
+    /// RTTs are measured by timestamps encoded in probes.
+    #[structopt(long, conflicts_with = "encode-timestamp")]
+    pub adaptive_wait: bool,
+    /// Quantile of RTTs in (0, 1] for `--adaptive-wait`.
+    #[structopt(long, default_value = "0.99", parse(try_from_str = parse_quantile))]
+    pub rtt_quantile: f64,
+
+    // Monitoring
+    /// Scan repeatedly, starting a cycle every given seconds.
+    #[structopt(long)]
+    pub interval: Option<u64>,
+    /// Stop monitoring after given cycles.
+    #[structopt(long, requires = "interval")]
+    pub cycles: Option<u64>,
+    /// Directory to save results and manifest of each cycle.
+    #[structopt(long, default_value = "fr-cycles")]
+    pub cycle_dir: PathBuf,
+    /// File to append change events as JSON lines, or `unix:<path>` for a Unix socket.
+    #[structopt(long, requires = "interval")]
+    pub events: Option<String>,
+
+    // Control
+    /// Serve the HTTP control and status API on the given address, e.g., `127.0.0.1:9100`.
+    #[structopt(long)]
+    pub http_addr: Option<std::net::SocketAddr>,
+    /// Join the coordinator at `<host>:<port>` or `unix:<path>` as a worker, which probes a share
+    /// of targets and shares preprobing distances and backward stops with other workers.
+    #[structopt(long, conflicts_with_all = &["interval", "replay"])]
+    pub coordinator: Option<String>,
 
     // Connection
     #[structopt(long, parse(try_from_str = utils::get_interface), default_value = "")]
+    #[serde(serialize_with = "interface_name")]
     pub interface: pnet::datalink::NetworkInterface,
+    /// Local source addresses to spread probes over, e.g., `10.0.0.2,10.0.0.3`, instead of the
+    /// address of `--interface` only. Probes leave by the route to each destination, whatever
+    /// their source.
+    #[structopt(long, use_delimiter = true)]
+    pub source_addrs: Vec<std::net::Ipv4Addr>,
+    /// Selection of the source address of each probe, `hash` of the destination keeps the flow of
+    /// each destination stable, while `round-robin` spreads evenly.
+    #[structopt(long, default_value = "hash")]
+    pub source_select: SourceSelect,
     #[structopt(long, default_value = "33434")]
     pub dst_port: u16,
     #[structopt(long, default_value = "How are you?")]
     pub payload_message: String,
 
     // Output
-    #[structopt(long = "no-dot", parse(from_flag = std::ops::Not::not))]
-    pub dot: bool,
-    #[structopt(long = "no-plot", parse(from_flag = std::ops::Not::not))]
-    pub plot: bool,
-    #[structopt(short = "o", long, default_value = "fr.dot")]
-    pub output_dot: PathBuf,
-    #[structopt(short = "O", long, default_value = "fr.png")]
-    pub output_viz: PathBuf,
+    #[structopt(flatten)]
+    #[serde(flatten)]
+    pub output: OutputOpt,
+    #[structopt(long = "no-manifest", parse(from_flag = std::ops::Not::not))]
+    pub manifest: bool,
+    #[structopt(long, default_value = "fr.json")]
+    pub output_manifest: PathBuf,
+    #[structopt(long = "no-results", parse(from_flag = std::ops::Not::not))]
+    pub results: bool,
+    #[structopt(long, default_value = "fr.results")]
+    pub output_results: PathBuf,
+    /// Capture all probes and replies to a pcapng file.
+    #[structopt(long)]
+    pub pcap_out: Option<PathBuf>,
 
     // Plot
-    #[structopt(long, default_value = "neato")]
-    pub layout: String,
-    #[structopt(long = "no-spline", parse(from_flag = std::ops::Not::not))]
-    pub spline: bool,
     #[structopt(short = "p", long)]
     pub plot_optimized: bool,
 
     // Misc
     #[structopt(long, default_value = "114514")]
     pub seed: u64,
+    #[structopt(long = "no-shuffle", parse(from_flag = std::ops::Not::not))]
+    pub shuffle: bool,
     #[structopt(long, default_value = "0")]
     pub salt: u16,
+    /// Secret key of probe MACs in 32 hex digits, random for each run if not given. It's
+    /// required to authenticate replies in `--replay`.
+    #[structopt(long)]
+    #[serde(skip)]
+    pub probe_secret: Option<ProbeKey>,
+    /// Save the probe secret in the manifest for `--replay`, instead of its fingerprint only.
+    /// Anyone holding the manifest can then forge replies to this run.
+    #[structopt(long)]
+    pub save_probe_secret: bool,
+    /// Accept replies whose quoted probe is rewritten past the probe MACs by NATs, i.e., a
+    /// translated source port along with the source address, a cleared checksum, or a rewritten
+    /// IP ID whose TTL is then recovered by the checksum MAC. It weakens authentication.
+    #[structopt(long)]
+    pub recover_rewrites: bool,
     #[structopt(long)]
     pub dry_run: bool,
+    /// Rebuild topology from replies in a pcap or pcapng capture, without sending any probe.
+    #[structopt(long, conflicts_with = "interval")]
+    pub replay: Option<PathBuf>,
     #[structopt(long)]
     pub dump_targets: Option<PathBuf>,
+    #[structopt(long)]
+    pub dump_stops: Option<PathBuf>,
+    /// Save a sample of replies discarded by the parser to a pcap file.
+    #[structopt(long)]
+    pub dump_errors: Option<PathBuf>,
+    #[structopt(long, default_value = "1000")]
+    pub dump_errors_max: u64,
     #[structopt(short = "D", long)]
     pub debug: bool,
+    /// Show a full-screen dashboard instead of progress bars and log lines if stdout is a TTY.
+    #[structopt(long)]
+    pub tui: bool,
+    /// TOML config file, overridden by command line flags.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+    /// Profile in the config file.
+    #[structopt(long, requires = "config")]
+    pub profile: Option<String>,
 
     // Target
     #[structopt(short, long, default_value = "8")]
     pub grain: u8,
     #[structopt(parse(try_from_str = parse_targets))]
+    #[serde(serialize_with = "display")]
     pub targets: Targets,
     #[structopt(long)]
+    pub lenient: bool,
+    #[structopt(long)]
+    pub hitlist: Option<PathBuf>,
+    #[structopt(long)]
     pub global_only: bool,
     #[structopt(long)]
     pub allow_private: bool,
+    #[structopt(long, parse(try_from_str = AddrSet::from_file))]
+    #[serde(serialize_with = "debug")]
+    pub blocklist: Option<AddrSet>,
+    #[structopt(long, parse(try_from_str = AddrSet::from_file))]
+    #[serde(serialize_with = "debug")]
+    pub allowlist: Option<AddrSet>,
 
     // Generated
     #[structopt(skip = ("0.0.0.0".parse::<std::net::Ipv4Addr>().unwrap()))]
     pub local_addr: std::net::Ipv4Addr,
+    /// Only its fingerprint, unless `--save-probe-secret`.
+    #[structopt(skip)]
+    #[serde(rename = "probe_secret_fingerprint", serialize_with = "fingerprint")]
+    pub probe_key: ProbeKey,
+    /// Saved apart in the manifest.
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub effective_args: Vec<String>,
+    /// Whether to skip rendering after scanning, i.e., the `scan` subcommand.
+    #[structopt(skip)]
+    pub scan_only: bool,
+}
+
+/// Options for rendering topology.
+#[derive(Debug, StructOpt, Serialize)]
+pub struct OutputOpt {
+    #[structopt(long = "no-dot", parse(from_flag = std::ops::Not::not))]
+    pub dot: bool,
+    #[structopt(long = "no-plot", parse(from_flag = std::ops::Not::not))]
+    pub plot: bool,
+    #[structopt(short = "o", long, default_value = "fr.dot")]
+    pub output_dot: PathBuf,
+    #[structopt(short = "O", long, default_value = "fr.png")]
+    pub output_viz: PathBuf,
+    #[structopt(long, default_value = "neato")]
+    pub layout: String,
+    #[structopt(long = "no-spline", parse(from_flag = std::ops::Not::not))]
+    pub spline: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RenderOpt {
+    /// Raw results of a scan.
+    pub input: PathBuf,
+    #[structopt(flatten)]
+    pub output: OutputOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CoordinateOpt {
+    /// Listen on `<host>:<port>` or `unix:<path>` for workers.
+    #[structopt(long, default_value = "127.0.0.1:9200")]
+    pub listen: String,
+    /// Number of workers to wait for, among which targets are split.
+    #[structopt(long, parse(try_from_str = parse_workers))]
+    pub workers: u64,
+    /// Save raw results merged from all workers.
+    #[structopt(long, default_value = "fr.results")]
+    pub output_results: PathBuf,
+    /// Stop waiting for workers to be done after this many secs since all joined, and merge
+    /// the results received so far. Ctrl-C does the same at any time.
+    #[structopt(long)]
+    pub timeout: Option<u64>,
+    #[structopt(flatten)]
+    pub output: OutputOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AnalyzeOpt {
+    /// Raw results of a scan.
+    pub input: PathBuf,
+    #[structopt(long, default_value = "10")]
+    pub top: usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffOpt {
+    /// Raw results of the old scan.
+    pub old: PathBuf,
+    /// Raw results of the new scan.
+    pub new: PathBuf,
+    /// `text` or `json`.
+    #[structopt(long, default_value = "text")]
+    pub format: DiffFormat,
+    /// Milliseconds of median RTT shift of an interface to be reported.
+    #[structopt(long, default_value = "10")]
+    pub rtt_threshold: u16,
+    /// Save the colored union of both topologies.
+    #[structopt(short = "o", long)]
+    pub output_dot: Option<PathBuf>,
+    /// Plot the colored union of both topologies, requiring `--output-dot`.
+    #[structopt(short = "O", long, requires = "output-dot")]
+    pub output_viz: Option<PathBuf>,
+    #[structopt(long, default_value = "neato")]
+    pub layout: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for DiffFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceSelect {
+    Hash,
+    RoundRobin,
+}
+
+impl std::str::FromStr for SourceSelect {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(Self::Hash),
+            "round-robin" => Ok(Self::RoundRobin),
+            _ => Err(format!("unknown source selection `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for SourceSelect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Hash => write!(f, "hash"),
+            Self::RoundRobin => write!(f, "round-robin"),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about)]
+pub enum Command {
+    /// Probe targets, then save raw results and the manifest. Without any subcommand,
+    /// topology is also rendered after scanning.
+    Scan(Box<Opt>),
+    /// Render topology from raw results, requiring neither root permission nor network.
+    Render(RenderOpt),
+    /// Print summary statistics of raw results.
+    Analyze(AnalyzeOpt),
+    /// Compare raw results of two scans.
+    Diff(DiffOpt),
+    /// Split targets among workers joining with `--coordinator`, relay what they share, and
+    /// merge their results into a single topology.
+    Coordinate(CoordinateOpt),
+}
+
+impl Opt {
+    /// Effective options, for the manifest, where a flag added later is never left out.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(self).unwrap_or_else(|e| {
+            log::warn!("Failed to serialize options: {}", e);
+            serde_json::Value::Null
+        });
+        if self.save_probe_secret {
+            json["probe_secret"] = self.probe_key.to_string().into();
+        }
+        json
+    }
+}
+
+fn display<T, S>(value: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    T: std::fmt::Display,
+    S: Serializer,
+{
+    serializer.collect_str(value)
+}
+
+fn debug<T, S>(value: &Option<T>, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    T: std::fmt::Debug,
+    S: Serializer,
+{
+    match value {
+        Some(value) => serializer.collect_str(&format_args!("{:?}", value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn interface_name<S: Serializer>(
+    interface: &pnet::datalink::NetworkInterface,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&interface.name)
+}
+
+fn fingerprint<S: Serializer>(
+    key: &ProbeKey,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&key.fingerprint())
 }
 
 #[derive(Debug, Clone)]
@@ -88,6 +399,15 @@ pub enum Targets {
     List(PathBuf),
 }
 
+impl std::fmt::Display for Targets {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Net(net) => write!(f, "{}", net),
+            Self::List(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 pub fn parse_targets(arg: &str) -> Result<Targets> {
     if let Ok(net) = arg.parse() {
         Ok(Targets::Net(net))
@@ -98,9 +418,82 @@ pub fn parse_targets(arg: &str) -> Result<Targets> {
     }
 }
 
-pub fn get_opt() -> Opt {
-    let mut opt: Opt = Opt::from_args();
-    opt.local_addr = crate::utils::get_interface_ipv4_addr(&opt.interface).unwrap();
+fn parse_quantile(arg: &str) -> std::result::Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(q) if q > 0.0 && q <= 1.0 => Ok(q),
+        _ => Err(format!("expected a quantile in (0, 1], got `{}`", arg)),
+    }
+}
+
+fn parse_workers(arg: &str) -> std::result::Result<u64, String> {
+    match arg.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "expected a positive number of workers, got `{}`",
+            arg
+        )),
+    }
+}
+
+pub fn get_command() -> Command {
+    let mut args: Vec<String> = std::env::args().collect();
+    let subcommand = args.get(1).cloned().unwrap_or_default();
+    match subcommand.as_str() {
+        "render" | "analyze" | "diff" | "coordinate" | "help" | "-h" | "--help" | "-V"
+        | "--version" => Command::from_iter(args),
+        _ => {
+            // scan, or the legacy usage without subcommands
+            let scan_only = subcommand == "scan";
+            if scan_only {
+                args.remove(1);
+            }
+            let mut opt = get_opt(args);
+            opt.scan_only = scan_only;
+            Command::Scan(Box::new(opt))
+        }
+    }
+}
+
+/// The first of `--source-addrs` is the vantage point, which defaults to the address of
+/// `--interface`.
+fn set_source_addrs(opt: &mut Opt) {
+    match opt.source_addrs.first() {
+        Some(&addr) => opt.local_addr = addr,
+        None => {
+            opt.local_addr = crate::utils::get_interface_ipv4_addr(&opt.interface).unwrap();
+            opt.source_addrs = vec![opt.local_addr];
+        }
+    }
+}
+
+/// Mask the value of `--probe-secret`, as the arguments are saved in the manifest and the dot
+/// file.
+fn mask_probe_secret(args: Vec<String>) -> Vec<String> {
+    let mut secret_next = false;
+    args.into_iter()
+        .map(|arg| {
+            if std::mem::replace(&mut secret_next, false) {
+                "<masked>".to_owned()
+            } else if arg == "--probe-secret" {
+                secret_next = true;
+                arg
+            } else if arg.starts_with("--probe-secret=") {
+                "--probe-secret=<masked>".to_owned()
+            } else {
+                arg
+            }
+        })
+        .collect()
+}
+
+pub fn get_opt(args: Vec<String>) -> Opt {
+    let args = config::merge_args(args).unwrap_or_else(|e| {
+        structopt::clap::Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+    let mut opt: Opt = Opt::from_iter(args.iter());
+    opt.effective_args = mask_probe_secret(args);
+    set_source_addrs(&mut opt);
+    opt.probe_key = opt.probe_secret.unwrap_or_else(ProbeKey::random);
     if opt.probing_rate == 0 {
         log::warn!("Probing rate is 0, rate limit will be turned off.");
         opt.probing_rate = u64::MAX;
@@ -112,8 +505,50 @@ pub fn get_opt() -> Opt {
 }
 
 pub fn get_test_opt() -> Opt {
-    let args = [env!("CARGO_PKG_NAME"), "192.168.1.1/24", "-g=8"];
+    let args = [
+        env!("CARGO_PKG_NAME"),
+        "192.168.1.1/24",
+        "-g=8",
+        "--probe-secret",
+        "000102030405060708090a0b0c0d0e0f",
+    ];
     let mut opt: Opt = Opt::from_iter(args.iter());
-    opt.local_addr = crate::utils::get_interface_ipv4_addr(&opt.interface).unwrap();
+    opt.effective_args = mask_probe_secret(args.iter().map(|a| a.to_string()).collect());
+    set_source_addrs(&mut opt);
+    opt.probe_key = opt.probe_secret.unwrap_or_default();
     opt
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_workers() {
+        assert_eq!(parse_workers("2"), Ok(2));
+        assert!(parse_workers("0").is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut opt = get_test_opt();
+        opt.probe_key = "000102030405060708090a0b0c0d0e0f".parse().unwrap();
+        let json = opt.to_json();
+        assert_eq!(json["targets"], "192.168.1.1/24");
+        assert_eq!(json["grain"], 8);
+        assert_eq!(json["source_select"], "hash");
+        // flattened
+        assert_eq!(json["output_dot"], "fr.dot");
+        assert_eq!(
+            json["probe_secret_fingerprint"],
+            opt.probe_key.fingerprint()
+        );
+        assert!(json.get("probe_secret").is_none());
+
+        opt.save_probe_secret = true;
+        assert_eq!(
+            opt.to_json()["probe_secret"],
+            "000102030405060708090a0b0c0d0e0f"
+        );
+    }
+}
@@ -0,0 +1,541 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        mpsc as std_mpsc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{error::*, prober::ProbePhase, OPT};
+
+lazy_static! {
+    /// Sample of replies discarded by the parser, if `--dump-errors` is given.
+    static ref ERROR_SAMPLES: Option<Mutex<PcapWriter<File>>> =
+        OPT.dump_errors.as_ref().and_then(|path| open_error_samples(path));
+    static ref ERROR_SAMPLE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+    /// Capture of all probes and replies, if `--pcap-out` is given.
+    static ref CAPTURE: Option<Capture> =
+        OPT.pcap_out.as_ref().and_then(|path| Capture::open(path));
+}
+
+enum CaptureRecord {
+    Packet(SystemTime, Direction, ProbePhase, Vec<u8>),
+    Flush(std_mpsc::Sender<()>),
+}
+
+/// Queue of packets to a writer thread, which keeps the file off the sending path.
+struct Capture {
+    tx: mpsc::Sender<CaptureRecord>,
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    const QUEUE_SIZE: usize = 65536;
+
+    fn open(path: &Path) -> Option<Self> {
+        let mut writer = match File::create(path).and_then(|f| PcapngWriter::new(BufWriter::new(f)))
+        {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::error!("cannot capture packets to {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let (tx, mut rx) = mpsc::channel(Self::QUEUE_SIZE);
+        std::thread::spawn(move || {
+            while let Some(record) = rx.blocking_recv() {
+                let result = match record {
+                    CaptureRecord::Packet(time, direction, phase, packet) => {
+                        writer.write_packet(time, direction, phase, &packet)
+                    }
+                    CaptureRecord::Flush(done_tx) => {
+                        let result = writer.flush();
+                        let _ = done_tx.send(());
+                        result
+                    }
+                };
+                if let Err(e) = result {
+                    log::error!("failed to capture packets: {}", e);
+                    return;
+                }
+            }
+        });
+
+        Some(Self {
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+    }
+}
+
+/// Flush the capture when dropped, e.g., on any exit from the scan.
+pub struct CaptureGuard;
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        flush_capture();
+    }
+}
+
+fn open_error_samples(path: &Path) -> Option<Mutex<PcapWriter<File>>> {
+    match File::create(path).and_then(PcapWriter::new) {
+        Ok(writer) => Some(Mutex::new(writer)),
+        Err(e) => {
+            log::error!("cannot dump errors to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Writer of the classic libpcap format, with raw IPv4 packets as records.
+pub struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    const MAGIC: u32 = 0xa1b2_c3d4;
+    const SNAPLEN: u32 = 65535;
+    const LINKTYPE_RAW: u32 = 101;
+
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&Self::MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&Self::SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&Self::LINKTYPE_RAW.to_le_bytes());
+        inner.write_all(&header)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn write_packet(&mut self, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap();
+        let len = packet.len().min(Self::SNAPLEN as usize);
+        let mut record = Vec::with_capacity(16 + len);
+        record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(len as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..len]);
+        self.inner.write_all(&record)?;
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Inbound = 1,
+    Outbound = 2,
+}
+
+/// Writer of the pcapng format, with raw IPv4 packets annotated with direction and phase.
+pub struct PcapngWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+    const INTERFACE_DESCRIPTION: u32 = 1;
+    const ENHANCED_PACKET: u32 = 6;
+    const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+    const SNAPLEN: u32 = 65535;
+    const LINKTYPE_RAW: u16 = 101;
+
+    const OPT_END: u16 = 0;
+    const OPT_COMMENT: u16 = 1;
+    const OPT_EPB_FLAGS: u16 = 2;
+
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&Self::BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // unknown section length
+        Self::write_block(&mut inner, Self::SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&Self::LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&Self::SNAPLEN.to_le_bytes());
+        Self::write_block(&mut inner, Self::INTERFACE_DESCRIPTION, &idb)?;
+
+        Ok(Self { inner })
+    }
+
+    fn pad(buf: &mut Vec<u8>) {
+        buf.resize((buf.len() + 3) & !3, 0);
+    }
+
+    fn write_block(inner: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_len = (12 + body.len()) as u32;
+        let mut block = Vec::with_capacity(total_len as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_len.to_le_bytes());
+        inner.write_all(&block)
+    }
+
+    fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+        body.extend_from_slice(&code.to_le_bytes());
+        body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        body.extend_from_slice(value);
+        Self::pad(body);
+    }
+
+    pub fn write_packet(
+        &mut self,
+        time: SystemTime,
+        direction: Direction,
+        phase: ProbePhase,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        let micros = time.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+        let len = packet.len().min(Self::SNAPLEN as usize);
+
+        let mut body = Vec::with_capacity(48 + len);
+        body.extend_from_slice(&0u32.to_le_bytes()); // interface id
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(len as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet[..len]);
+        Self::pad(&mut body);
+        Self::push_option(
+            &mut body,
+            Self::OPT_EPB_FLAGS,
+            &(direction as u32).to_le_bytes(),
+        );
+        let comment = match phase {
+            ProbePhase::Pre => "phase=pre",
+            ProbePhase::Main => "phase=main",
+        };
+        Self::push_option(&mut body, Self::OPT_COMMENT, comment.as_bytes());
+        Self::push_option(&mut body, Self::OPT_END, &[]);
+
+        Self::write_block(&mut self.inner, Self::ENHANCED_PACKET, &body)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Record a probe or reply to `--pcap-out`, or drop it if the writer falls behind.
+pub fn capture(direction: Direction, phase: ProbePhase, packet: &[u8]) {
+    if let Some(capture) = CAPTURE.as_ref() {
+        let record = CaptureRecord::Packet(SystemTime::now(), direction, phase, packet.to_vec());
+        if capture.tx.try_send(record).is_err() {
+            capture.dropped.fetch_add(1, SeqCst);
+        }
+    }
+}
+
+/// Wait for queued packets to be written to `--pcap-out`.
+pub fn flush_capture() {
+    if let Some(capture) = CAPTURE.as_ref() {
+        let (done_tx, done_rx) = std_mpsc::channel();
+        // the queue may be full, retry until the writer catches up or is gone
+        let mut record = CaptureRecord::Flush(done_tx);
+        loop {
+            match capture.tx.try_send(record) {
+                Ok(()) => break,
+                Err(mpsc::error::TrySendError::Full(r)) => {
+                    record = r;
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            }
+        }
+        if done_rx.recv().is_err() {
+            log::error!("failed to flush packet capture");
+        }
+        let dropped = capture.dropped.load(SeqCst);
+        if dropped > 0 {
+            log::warn!(
+                "{} packets are missing from the capture, which fell behind",
+                dropped
+            );
+        }
+    }
+}
+
+/// A packet read from a capture, with the link layer stripped.
+#[derive(Debug)]
+pub struct CapturedPacket {
+    pub time_ms: u64,
+    /// From the comment of packets captured by `--pcap-out`.
+    pub phase: Option<ProbePhase>,
+    pub data: Vec<u8>,
+}
+
+fn read_u16(buf: &[u8], at: usize, le: bool) -> Option<u16> {
+    let bytes = [*buf.get(at)?, *buf.get(at + 1)?];
+    Some(if le {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_u32(buf: &[u8], at: usize, le: bool) -> Option<u32> {
+    let bytes = [
+        *buf.get(at)?,
+        *buf.get(at + 1)?,
+        *buf.get(at + 2)?,
+        *buf.get(at + 3)?,
+    ];
+    Some(if le {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+/// IPv4 packet in a frame of `linktype`, or `None` if it's something else.
+fn strip_link_layer(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+    let (offset, ethertype_at) = match linktype {
+        101 | 228 => return Some(frame), // raw ip, ipv4
+        1 => (14, 12),                   // ethernet
+        113 => (16, 14),                 // linux cooked
+        _ => return None,
+    };
+    if frame.len() >= offset && frame[ethertype_at..ethertype_at + 2] == ETHERTYPE_IPV4 {
+        Some(&frame[offset..])
+    } else {
+        None
+    }
+}
+
+fn parse_pcap(buf: &[u8]) -> Option<Vec<CapturedPacket>> {
+    let (le, nanos) = match read_u32(buf, 0, true)? {
+        0xa1b2_c3d4 => (true, false),
+        0xd4c3_b2a1 => (false, false),
+        0xa1b2_3c4d => (true, true),
+        0x4d3c_b2a1 => (false, true),
+        _ => return None,
+    };
+    let linktype = read_u32(buf, 20, le)?;
+
+    let mut packets = Vec::new();
+    let mut at = 24;
+    while at < buf.len() {
+        let secs = read_u32(buf, at, le)? as u64;
+        let frac = read_u32(buf, at + 4, le)? as u64;
+        let len = read_u32(buf, at + 8, le)? as usize;
+        let frame = buf.get(at + 16..at + 16 + len)?;
+        if let Some(data) = strip_link_layer(linktype, frame) {
+            packets.push(CapturedPacket {
+                time_ms: secs * 1000 + if nanos { frac / 1_000_000 } else { frac / 1000 },
+                phase: None,
+                data: data.to_vec(),
+            });
+        }
+        at += 16 + len;
+    }
+    Some(packets)
+}
+
+fn parse_pcapng(buf: &[u8]) -> Option<Vec<CapturedPacket>> {
+    const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+    const INTERFACE_DESCRIPTION: u32 = 1;
+    const ENHANCED_PACKET: u32 = 6;
+    const OPT_COMMENT: u16 = 1;
+    const OPT_IF_TSRESOL: u16 = 9;
+
+    // (linktype, units of timestamps per second) of each interface
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut packets = Vec::new();
+    let mut le = true;
+    let mut at = 0;
+    while at < buf.len() {
+        let block_type = read_u32(buf, at, le)?;
+        if block_type == SECTION_HEADER {
+            le = match read_u32(buf, at + 8, true)? {
+                0x1a2b_3c4d => true,
+                0x4d3c_2b1a => false,
+                _ => return None,
+            };
+            interfaces.clear();
+        }
+        let len = read_u32(buf, at + 4, le)? as usize;
+        if len < 12 || len & 3 != 0 {
+            return None;
+        }
+        let body = buf.get(at + 8..at + len - 4)?;
+
+        // options are (code, length, value padded to 4 bytes)
+        let options = |mut i: usize| {
+            let mut options = Vec::new();
+            while let (Some(code), Some(len)) = (read_u16(body, i, le), read_u16(body, i + 2, le)) {
+                let value = body.get(i + 4..i + 4 + len as usize).unwrap_or_default();
+                options.push((code, value));
+                i += 4 + ((len as usize + 3) & !3);
+            }
+            options
+        };
+
+        match block_type {
+            INTERFACE_DESCRIPTION => {
+                let linktype = read_u16(body, 0, le)? as u32;
+                let mut units = 1_000_000;
+                for (code, value) in options(8) {
+                    if code == OPT_IF_TSRESOL && !value.is_empty() {
+                        units = if value[0] & 0x80 == 0 {
+                            10u64.checked_pow(value[0] as u32)?
+                        } else {
+                            1u64.checked_shl((value[0] & 0x7f) as u32)?
+                        };
+                    }
+                }
+                interfaces.push((linktype, units));
+            }
+            ENHANCED_PACKET => {
+                let &(linktype, units) = interfaces.get(read_u32(body, 0, le)? as usize)?;
+                let ts = (read_u32(body, 4, le)? as u64) << 32 | read_u32(body, 8, le)? as u64;
+                let caplen = read_u32(body, 12, le)? as usize;
+                let frame = body.get(20..20 + caplen)?;
+                let mut phase = None;
+                for (code, value) in options(20 + ((caplen + 3) & !3)) {
+                    match (code, value) {
+                        (OPT_COMMENT, b"phase=pre") => phase = Some(ProbePhase::Pre),
+                        (OPT_COMMENT, b"phase=main") => phase = Some(ProbePhase::Main),
+                        _ => {}
+                    }
+                }
+                if let Some(data) = strip_link_layer(linktype, frame) {
+                    packets.push(CapturedPacket {
+                        time_ms: (ts as u128 * 1000 / units as u128) as u64,
+                        phase,
+                        data: data.to_vec(),
+                    });
+                }
+            }
+            _ => {}
+        }
+        at += len;
+    }
+    Some(packets)
+}
+
+/// Read IPv4 packets from a pcap or pcapng file.
+pub fn read_capture(path: &Path) -> Result<Vec<CapturedPacket>> {
+    let buf = std::fs::read(path)?;
+    let packets = if read_u32(&buf, 0, true) == Some(0x0a0d_0d0a) {
+        parse_pcapng(&buf)
+    } else {
+        parse_pcap(&buf)
+    };
+    packets.ok_or_else(|| Error::InvalidCapture(path.display().to_string()))
+}
+
+/// Save a discarded reply to `--dump-errors`, up to `--dump-errors-max` ones.
+pub fn sample_error(packet: &[u8]) {
+    if let Some(writer) = ERROR_SAMPLES.as_ref() {
+        if ERROR_SAMPLE_COUNT.fetch_add(1, SeqCst) < OPT.dump_errors_max {
+            let _ = writer
+                .lock()
+                .unwrap()
+                .write_packet(SystemTime::now(), packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet(
+                UNIX_EPOCH + std::time::Duration::from_micros(1_500_000),
+                &[0x45; 28],
+            )
+            .unwrap();
+        let buf = writer.into_inner();
+
+        assert_eq!(buf.len(), 24 + 16 + 28);
+        assert_eq!(&buf[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&buf[20..24], &101u32.to_le_bytes());
+        assert_eq!(&buf[24..28], &1u32.to_le_bytes());
+        assert_eq!(&buf[28..32], &500_000u32.to_le_bytes());
+        assert_eq!(&buf[32..36], &28u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_write_pcapng() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet(
+                UNIX_EPOCH,
+                Direction::Outbound,
+                ProbePhase::Pre,
+                &[0x45; 30],
+            )
+            .unwrap();
+        let buf = writer.into_inner();
+
+        // walk through blocks by their lengths, which are repeated at both ends
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let mut offset = 0;
+        let mut types = Vec::new();
+        while offset < buf.len() {
+            let len = u32_at(offset + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(offset + len - 4) as usize, len);
+            types.push(u32_at(offset));
+            offset += len;
+        }
+        assert_eq!(offset, buf.len());
+        assert_eq!(types, vec![0x0a0d_0d0a, 1, 6]);
+    }
+
+    #[test]
+    fn test_read() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_234_567);
+
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet(time, Direction::Inbound, ProbePhase::Pre, &[0x45; 29])
+            .unwrap();
+        writer
+            .write_packet(time, Direction::Inbound, ProbePhase::Main, &[0x45; 30])
+            .unwrap();
+        let packets = parse_pcapng(&writer.into_inner()).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].time_ms, 1_234_567);
+        assert!(matches!(packets[0].phase, Some(ProbePhase::Pre)));
+        assert!(matches!(packets[1].phase, Some(ProbePhase::Main)));
+        assert_eq!(packets[1].data, vec![0x45; 30]);
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_packet(time, &[0x45; 29]).unwrap();
+        let packets = parse_pcap(&writer.into_inner()).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].time_ms, 1_234_567);
+        assert!(packets[0].phase.is_none());
+
+        assert!(parse_pcap(&[0u8; 24]).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Pseudo-random permutation of `0..n` by iterating the multiplicative group of integers
+/// modulo a prime `p > n`, like ZMap does. Only O(1) memory is required, and consecutive
+/// outputs are spread evenly over the whole range.
+#[derive(Debug, Clone)]
+pub struct CyclicPermutation {
+    n: u64,
+    prime: u64,
+    generator: u64,
+    first: u64,
+    current: u64,
+    remaining: u64,
+}
+
+impl CyclicPermutation {
+    pub fn new(n: usize, seed: u64) -> Self {
+        let n = n as u64;
+        let prime = next_prime(n);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let generator = if prime <= 2 {
+            1
+        } else {
+            let factors = prime_factors(prime - 1);
+            loop {
+                let g = rng.gen_range(2, prime);
+                if factors
+                    .iter()
+                    .all(|&q| pow_mod(g, (prime - 1) / q, prime) != 1)
+                {
+                    break g;
+                }
+            }
+        };
+        let first = if prime <= 2 {
+            1
+        } else {
+            rng.gen_range(1, prime)
+        };
+
+        Self {
+            n,
+            prime,
+            generator,
+            first,
+            current: first,
+            remaining: prime - 1,
+        }
+    }
+}
+
+impl Iterator for CyclicPermutation {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            let x = self.current;
+            self.current = mul_mod(self.current, self.generator, self.prime);
+            self.remaining -= 1;
+            if x <= self.n {
+                return Some((x - 1) as usize);
+            }
+        }
+        None
+    }
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    ((a as u128 * b as u128) % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+// `is_multiple_of` requires a newer toolchain than supported, whose clippy doesn't know the lint
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    let mut i = 2;
+    while i * i <= n {
+        if n % i == 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// The smallest prime strictly greater than `n`.
+fn next_prime(n: u64) -> u64 {
+    let mut p = n + 1;
+    while !is_prime(p) {
+        p += 1;
+    }
+    p
+}
+
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn prime_factors(mut n: u64) -> Vec<u64> {
+    let mut factors = Vec::new();
+    let mut i = 2;
+    while i * i <= n {
+        if n % i == 0 {
+            factors.push(i);
+            while n % i == 0 {
+                n /= i;
+            }
+        }
+        i += 1;
+    }
+    if n > 1 {
+        factors.push(n);
+    }
+    factors
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_permutation() {
+        for &n in &[0usize, 1, 2, 3, 10, 256, 1000] {
+            let mut perm: Vec<_> = CyclicPermutation::new(n, 42).collect();
+            assert_eq!(perm.len(), n);
+            perm.sort_unstable();
+            assert!(perm.into_iter().eq(0..n));
+        }
+    }
+
+    #[test]
+    fn test_seed() {
+        let a: Vec<_> = CyclicPermutation::new(1000, 1).collect();
+        let b: Vec<_> = CyclicPermutation::new(1000, 1).collect();
+        let c: Vec<_> = CyclicPermutation::new(1000, 2).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fmt, net::Ipv4Addr, path::PathBuf};
+
+use hashbrown::hash_set::HashSet;
+use ipnet::Ipv4Net;
+
+use crate::error::*;
+
+/// Routed prefixes, e.g., from a BGP table dump, used for longest prefix matching.
+///
+/// Each line is either a network like `1.0.0.0/24`, or in CAIDA pfx2as format, i.e.,
+/// `1.0.0.0 24 13335` separated by whitespaces. Lines starting with `#` are ignored.
+#[derive(Clone, Default)]
+pub struct PrefixMap {
+    path: PathBuf,
+    by_len: Vec<HashSet<u32>>,
+    count: usize,
+}
+
+impl PrefixMap {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut map = Self::parse(&content)?;
+        map.path = path.into();
+        Ok(map)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut map = Self {
+            path: PathBuf::new(),
+            by_len: vec![HashSet::new(); 33],
+            count: 0,
+        };
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let net =
+                Self::parse_line(line).ok_or_else(|| Error::InvalidNetwork(line.to_owned()))?;
+            if map.by_len[net.prefix_len() as usize].insert(net.network().into()) {
+                map.count += 1;
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn parse_line(line: &str) -> Option<Ipv4Net> {
+        let mut fields = line.split_whitespace();
+        let first = fields.next()?;
+        if let Ok(net) = first.parse::<Ipv4Net>() {
+            return Some(net);
+        }
+        let addr: Ipv4Addr = first.parse().ok()?;
+        let len: u8 = fields.next()?.parse().ok()?;
+        Ipv4Net::new(addr, len).ok()
+    }
+
+    /// The longest prefix containing `addr`, if any.
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<Ipv4Net> {
+        let u: u32 = addr.into();
+        (0..=32u8).rev().find_map(|len| {
+            let mask = if len == 0 { 0 } else { !0u32 << (32 - len) };
+            let network = u & mask;
+            if self.by_len[len as usize].contains(&network) {
+                Some(Ipv4Net::new(network.into(), len).unwrap())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Whether two addresses fall into the same longest matching prefix. Addresses not covered
+    /// by any prefix are never in the same one.
+    pub fn same_prefix(&self, a: Ipv4Addr, b: Ipv4Addr) -> bool {
+        match self.lookup(a) {
+            Some(prefix) => self.lookup(b) == Some(prefix),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for PrefixMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrefixMap({:?}, {} prefixes)", self.path, self.count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let map = PrefixMap::parse(
+            "# pfx2as\n\
+             10.0.0.0\t8\t64512\n\
+             10.1.0.0/16\n\
+             0.0.0.0/0\n",
+        )
+        .unwrap();
+        let lookup = |s: &str| map.lookup(s.parse().unwrap()).unwrap().to_string();
+        assert_eq!(lookup("10.1.2.3"), "10.1.0.0/16");
+        assert_eq!(lookup("10.2.2.3"), "10.0.0.0/8");
+        assert_eq!(lookup("11.0.0.1"), "0.0.0.0/0");
+        assert!(map.same_prefix("10.1.0.1".parse().unwrap(), "10.1.255.1".parse().unwrap()));
+        assert!(!map.same_prefix("10.1.0.1".parse().unwrap(), "10.2.0.1".parse().unwrap()));
+
+        let map = PrefixMap::parse("10.0.0.0/8\n").unwrap();
+        assert!(!map.same_prefix("11.0.0.1".parse().unwrap(), "11.0.0.2".parse().unwrap()));
+    }
+}
@@ -1,7 +1,173 @@
 use crate::error::*;
 use crate::OPT;
-use pnet::packet::{icmp::*, ip::IpNextHeaderProtocols, ipv4::*, udp::*, Packet};
-use std::net::Ipv4Addr;
+use pnet::packet::{icmp::*, ip::IpNextHeaderProtocols, ipv4::*, udp::*, MutablePacket, Packet};
+use siphasher::sip::SipHasher24;
+use std::{hash::Hasher, net::Ipv4Addr};
+
+/// Secret key of probe MACs, with which replies to probes of other scanners or previous runs
+/// are rejected.
+#[derive(Default, Copy, Clone, PartialEq, Eq)]
+pub struct ProbeKey(pub u64, pub u64);
+
+/// Only the fingerprint, so that the key never goes to logs.
+impl std::fmt::Debug for ProbeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ProbeKey({})", self.fingerprint())
+    }
+}
+
+impl ProbeKey {
+    pub fn random() -> Self {
+        Self(rand::random(), rand::random())
+    }
+
+    fn mac(&self, data: &[u8]) -> u16 {
+        let mut hasher = SipHasher24::new_with_keys(self.0, self.1);
+        hasher.write(data);
+        hasher.finish() as u16
+    }
+
+    /// MAC over the destination, as the UDP source port so that the flow towards each
+    /// destination is kept stable for load balancers.
+    pub fn port_mac(&self, destination: Ipv4Addr, salt: u16) -> u16 {
+        let mut data = [0u8; 7];
+        data[..4].copy_from_slice(&destination.octets());
+        data[4..6].copy_from_slice(&salt.to_be_bytes());
+        data[6] = b'P';
+        self.mac(&data)
+    }
+
+    /// MAC over the destination, the TTL and the phase, as the UDP checksum. It's never zero,
+    /// which means no checksum.
+    pub fn checksum_mac(
+        &self,
+        destination: Ipv4Addr,
+        ttl: u8,
+        phase: ProbePhase,
+        salt: u16,
+    ) -> u16 {
+        let mut data = [0u8; 9];
+        data[..4].copy_from_slice(&destination.octets());
+        data[4] = ttl;
+        data[5] = phase as u8;
+        data[6..8].copy_from_slice(&salt.to_be_bytes());
+        data[8] = b'C';
+        match self.mac(&data) {
+            0 => 0xFFFF,
+            mac => mac,
+        }
+    }
+
+    /// Hash of the key, which tells whether two runs share a key without revealing it.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = SipHasher24::new();
+        hasher.write_u64(self.0);
+        hasher.write_u64(self.1);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+impl std::str::FromStr for ProbeKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("expected 32 hex digits, got `{}`", s);
+        if s.len() != 32 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let k0 = u64::from_str_radix(&s[..16], 16).map_err(|_| invalid())?;
+        let k1 = u64::from_str_radix(&s[16..], 16).map_err(|_| invalid())?;
+        Ok(Self(k0, k1))
+    }
+}
+
+impl std::fmt::Display for ProbeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:016x}{:016x}", self.0, self.1)
+    }
+}
+
+/// Ones' complement sum of 16-bit words.
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xFFFF) + (sum >> 16)) as u16
+}
+
+/// Undo incremental updates of a checksum, where each pair is a rewritten 16-bit word and the
+/// original one (RFC 1624).
+fn undo_rewrites(sum: u16, words: &[(u16, u16)]) -> u16 {
+    !words.iter().fold(!sum, |sum, &(rewritten, original)| {
+        ones_complement_add(ones_complement_add(sum, !rewritten), original)
+    })
+}
+
+/// Fields of a probe rewritten on the way as quoted in the reply, hinting NATs or other
+/// middleboxes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Rewrites(pub u8);
+
+impl Rewrites {
+    pub const SRC_ADDR: u8 = 1 << 0;
+    pub const SRC_PORT: u8 = 1 << 1;
+    pub const DST_PORT: u8 = 1 << 2;
+    pub const LENGTH: u8 = 1 << 3;
+    pub const IP_ID: u8 = 1 << 4;
+    pub const CHECKSUM: u8 = 1 << 5;
+
+    const NAMES: [(u8, &'static str); 6] = [
+        (Self::SRC_ADDR, "src_addr"),
+        (Self::SRC_PORT, "src_port"),
+        (Self::DST_PORT, "dst_port"),
+        (Self::LENGTH, "length"),
+        (Self::IP_ID, "ip_id"),
+        (Self::CHECKSUM, "checksum"),
+    ];
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    pub fn insert(&mut self, flag: u8) {
+        self.0 |= flag;
+    }
+}
+
+impl std::str::FromStr for Rewrites {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut rewrites = Self::default();
+        if s == "-" {
+            return Ok(rewrites);
+        }
+        for name in s.split(',') {
+            let &(flag, _) = Self::NAMES
+                .iter()
+                .find(|(_, n)| *n == name)
+                .ok_or_else(|| format!("unknown rewrite `{}`", name))?;
+            rewrites.insert(flag);
+        }
+        Ok(rewrites)
+    }
+}
+
+impl std::fmt::Display for Rewrites {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "-");
+        }
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct ProbeDebugResult {
@@ -14,18 +180,62 @@ pub struct ProbeResult {
     pub responder: Ipv4Addr,
     pub distance: u8,
     pub from_destination: bool,
+    /// Whether `distance` is inferred from the reply TTL since the quoted TTL is unusable.
+    pub distance_inferred: bool,
+    pub reply_ttl: u8,
+    /// TTL of the probe when quoted, normally 1 for time exceeded. Greater ones hint TTL
+    /// propagation quirks, e.g., by MPLS tunnels. It and the icmp type and code are unknown in
+    /// results of earlier versions.
+    pub quoted_ttl: Option<u8>,
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    pub rewrites: Rewrites,
     pub debug: ProbeDebugResult,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[cfg(test)]
+impl ProbeResult {
+    /// A reply from `responder` at `distance`, which is from the destination if it's `destination`.
+    pub fn for_test(destination: &str, responder: &str, distance: u8, rtt: u16) -> Self {
+        let from_destination = destination == responder;
+        Self {
+            destination: destination.parse().unwrap(),
+            responder: responder.parse().unwrap(),
+            distance,
+            from_destination,
+            distance_inferred: false,
+            reply_ttl: 64,
+            quoted_ttl: Some(1),
+            icmp_type: Some(if from_destination { 3 } else { 11 }),
+            icmp_code: Some(if from_destination { 3 } else { 0 }),
+            rewrites: Rewrites::default(),
+            debug: ProbeDebugResult { rtt },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ProbePhase {
     Pre = 0,
     Main = 1,
 }
 
+impl ProbePhase {
+    /// The phase carried by the IP ID of a probe.
+    fn from_ip_id(id: u16) -> Self {
+        if id & 0x20 == 0 {
+            ProbePhase::Pre
+        } else {
+            ProbePhase::Main
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Prober {
     pub phase: ProbePhase,
+    /// Whether to accept replies rewritten past the MACs, see `--recover-rewrites`.
+    recover_rewrites: bool,
 }
 
 impl Prober {
@@ -34,7 +244,10 @@ impl Prober {
     pub const PACK_BUFFER_LENGTH: usize = 256;
 
     pub fn new(phase: ProbePhase) -> Self {
-        Self { phase }
+        Self {
+            phase,
+            recover_rewrites: OPT.recover_rewrites,
+        }
     }
 }
 
@@ -55,7 +268,7 @@ impl Prober {
 
         let mut udp_packet =
             MutableUdpPacket::new(&mut buffer[Self::IPV4_HEADER_LENGTH as usize..]).unwrap();
-        udp_packet.set_source(crate::utils::ip_checksum(dst_ip, OPT.salt));
+        udp_packet.set_source(OPT.probe_key.port_mac(dst_ip, OPT.salt));
         udp_packet.set_destination(OPT.dst_port);
         udp_packet.set_length(expect_udp_size);
         udp_packet.set_payload(OPT.payload_message.as_bytes());
@@ -77,10 +290,30 @@ impl Prober {
         ip_packet.set_identification(ip_id);
         ip_packet.set_total_length(expect_total_size);
 
+        // the checksum carries a MAC, while still valid by adjusting the last 2 bytes of payload
+        let mac = OPT
+            .probe_key
+            .checksum_mac(dst_ip, ttl, self.phase, OPT.salt);
+        let udp_range = Self::IPV4_HEADER_LENGTH as usize..expect_total_size as usize;
+        let mut udp_packet = MutableUdpPacket::new(&mut buffer[udp_range]).unwrap();
+        let filler_range = expect_udp_size as usize - 2..expect_udp_size as usize;
+        udp_packet.packet_mut()[filler_range.clone()].copy_from_slice(&[0, 0]);
+        let sum = ipv4_checksum(&udp_packet.to_immutable(), &source_ip, &dst_ip);
+        let filler = !ones_complement_add(!sum, mac);
+        udp_packet.packet_mut()[filler_range].copy_from_slice(&filler.to_be_bytes());
+        udp_packet.set_checksum(mac);
+
         return expect_total_size as usize;
     }
 
-    pub fn parse(&self, packet: &[u8], ignore_port: bool) -> Result<ProbeResult> {
+    pub fn parse(&self, packet: &[u8], ignore_auth: bool) -> Result<ProbeResult> {
+        self.parse_at(packet, ignore_auth, crate::utils::timestamp_ms_u16())
+    }
+
+    /// Parse a reply received at `recv_ms`, the lower 16 bits of a unix timestamp in millis.
+    /// Unless `ignore_auth`, the quoted probe must carry MACs by `--probe-secret`, and only then
+    /// are rewrites of it detected.
+    pub fn parse_at(&self, packet: &[u8], ignore_auth: bool, recv_ms: u16) -> Result<ProbeResult> {
         // currently there's a bug in pnet, that ip total length has incorrect endianness on apple devices
         // thus, we can't...
         //  - construct res_ip_packet from ip_packet.payload()[ICMP_HDR_LEN..]
@@ -88,17 +321,37 @@ impl Prober {
 
         let ip_packet = Ipv4Packet::new(packet).ok_or(Error::ParseError(1))?;
         let icmp_packet = IcmpPacket::new(ip_packet.payload()).ok_or(Error::ParseError(2))?;
-        let res_ip_packet = Ipv4Packet::new(
-            &ip_packet.packet()[(Self::IPV4_HEADER_LENGTH + Self::ICMP_HEADER_LENGTH) as usize..],
-        )
-        .ok_or(Error::ParseError(3))?;
+        // a truncated capture may end right in the icmp header
+        let res_ip_packet = ip_packet
+            .packet()
+            .get((Self::IPV4_HEADER_LENGTH + Self::ICMP_HEADER_LENGTH) as usize..)
+            .and_then(Ipv4Packet::new)
+            .ok_or(Error::ParseError(3))?;
         let res_udp_packet = UdpPacket::new(res_ip_packet.payload()).ok_or(Error::ParseError(4))?;
 
         let destination = res_ip_packet.get_destination();
         let src_port = res_udp_packet.get_source();
-        let expected_src_port = crate::utils::ip_checksum(destination, OPT.salt);
-        if src_port != expected_src_port && !ignore_port {
-            return Err(Error::UnexpectedIcmpSrcPort(src_port, expected_src_port));
+        let expected_src_port = OPT.probe_key.port_mac(destination, OPT.salt);
+
+        // replies come back to the probing address, even if translated on the way
+        let local_addr = ip_packet.get_destination();
+        let mut rewrites = Rewrites::default();
+        if res_ip_packet.get_source() != local_addr {
+            rewrites.insert(Rewrites::SRC_ADDR);
+        }
+        if res_udp_packet.get_destination() != OPT.dst_port {
+            rewrites.insert(Rewrites::DST_PORT);
+        }
+        if !Self::is_valid_udp_length(res_udp_packet.get_length()) {
+            rewrites.insert(Rewrites::LENGTH);
+        }
+        let port_authentic = src_port == expected_src_port;
+        if !port_authentic && !ignore_auth {
+            // maybe translated by a NAPT along with the source address, otherwise forged
+            if !(self.recover_rewrites && rewrites.contains(Rewrites::SRC_ADDR)) {
+                return Err(Error::UnexpectedIcmpSrcPort(src_port, expected_src_port));
+            }
+            rewrites.insert(Rewrites::SRC_PORT);
         }
 
         // log::trace!("{:#?}", ip_packet);
@@ -106,7 +359,7 @@ impl Prober {
         // log::trace!("{:#?}", res_ip_packet);
         // log::trace!("{:#?}", res_udp_packet);
 
-        let initial_ttl = {
+        let mut initial_ttl = {
             let ttl = res_ip_packet.get_identification() & 0x1f;
             if ttl == 0 {
                 32
@@ -114,25 +367,75 @@ impl Prober {
                 ttl as u8
             }
         };
+        let phase = ProbePhase::from_ip_id(res_ip_packet.get_identification());
+        if !ignore_auth {
+            // undo fixups of the checksum by NATs, then it must be the mac
+            let mac = {
+                let (src, local) = (u32::from(res_ip_packet.get_source()), u32::from(local_addr));
+                let original = undo_rewrites(
+                    res_udp_packet.get_checksum(),
+                    &[
+                        ((src >> 16) as u16, (local >> 16) as u16),
+                        (src as u16, local as u16),
+                        (src_port, expected_src_port),
+                        (res_udp_packet.get_destination(), OPT.dst_port),
+                    ],
+                );
+                match original {
+                    0 => 0xFFFF,
+                    c => c,
+                }
+            };
+            let expected_mac =
+                OPT.probe_key
+                    .checksum_mac(destination, initial_ttl, phase, OPT.salt);
+            if mac != expected_mac {
+                // both weaken the checksum mac, thus the port mac must hold
+                if !(self.recover_rewrites && port_authentic) {
+                    return Err(Error::UnexpectedProbeMac(mac, expected_mac));
+                }
+                if res_udp_packet.get_checksum() == 0 {
+                    rewrites.insert(Rewrites::CHECKSUM);
+                } else if let Some(ttl) = self.recover_ttl(mac, destination) {
+                    // ttl is lost with the ip id, but recoverable by the mac
+                    rewrites.insert(Rewrites::IP_ID);
+                    initial_ttl = ttl;
+                } else {
+                    return Err(Error::UnexpectedProbeMac(mac, expected_mac));
+                }
+            } else if phase != self.phase {
+                // authentic but stale, e.g., a late reply to a preprobe
+                return Err(Error::UnexpectedProbePhase(phase, self.phase));
+            }
+        } else {
+            // anyone may have sent it, thus nothing is known to be rewritten
+            rewrites = Rewrites::default();
+        }
+
         let dst_ttl = res_ip_packet.get_ttl();
+        let reply_ttl = ip_packet.get_ttl();
 
         let icmp_type = icmp_packet.get_icmp_type();
         let icmp_code = icmp_packet.get_icmp_code();
 
-        let (distance, from_destination) = {
+        let (distance, from_destination, distance_inferred) = {
             if icmp_type == IcmpTypes::DestinationUnreachable && [1, 2, 3].contains(&icmp_code.0) {
-                if initial_ttl < dst_ttl {
+                if initial_ttl >= dst_ttl {
+                    (initial_ttl - dst_ttl + 1, true, false)
+                } else if let ProbePhase::Pre = self.phase {
+                    // quoted ttl is rewritten, while a rough distance is still useful for preprobing
+                    (crate::utils::infer_distance(reply_ttl), true, true)
+                } else {
                     return Err(Error::InvalidDistance(initial_ttl, dst_ttl));
                 }
-                (initial_ttl - dst_ttl + 1, true)
             } else if icmp_type == IcmpTypes::TimeExceeded {
-                (initial_ttl, false)
+                (initial_ttl, false, false)
             } else {
                 return Err(Error::UnexpectedIcmpType(icmp_type, icmp_code));
             }
         };
 
-        let rtt = if OPT.encode_timestamp {
+        let rtt = if OPT.encode_timestamp && !rewrites.contains(Rewrites::IP_ID) {
             let send = if cfg!(target_vendor = "apple") {
                 // byte order fix
                 ((res_ip_packet.get_identification() >> 6) & 0x3FF)
@@ -141,7 +444,7 @@ impl Prober {
                 ((res_ip_packet.get_identification() >> 6) & 0x3FF)
                     | (((res_ip_packet.get_total_length() >> 1) & 0x3F) << 10)
             } as u32;
-            let recv = crate::utils::timestamp_ms_u16() as u32;
+            let recv = recv_ms as u32;
             log::trace!("send: 0x{:x}, recv: 0x{:x}", send, recv);
 
             if recv >= send {
@@ -158,11 +461,48 @@ impl Prober {
             responder: ip_packet.get_source(),
             distance,
             from_destination,
+            distance_inferred,
+            reply_ttl,
+            quoted_ttl: Some(dst_ttl),
+            icmp_type: Some(icmp_type.0),
+            icmp_code: Some(icmp_code.0),
+            rewrites,
             debug: ProbeDebugResult { rtt },
         };
 
         Ok(result)
     }
+
+    /// Whether the UDP length is one of those `pack` may send.
+    /// Phase of the probe quoted in a reply, unauthenticated, e.g., for replies captured without
+    /// phases.
+    pub fn quoted_phase(packet: &[u8]) -> Option<ProbePhase> {
+        let ip_packet = Ipv4Packet::new(packet)?;
+        let res_ip_packet = ip_packet
+            .packet()
+            .get((Self::IPV4_HEADER_LENGTH + Self::ICMP_HEADER_LENGTH) as usize..)
+            .and_then(Ipv4Packet::new)?;
+        Some(ProbePhase::from_ip_id(res_ip_packet.get_identification()))
+    }
+
+    fn is_valid_udp_length(length: u16) -> bool {
+        // the quoted length is untrusted, thus never overflows
+        let total = length as u32 + Self::IPV4_HEADER_LENGTH as u32;
+        if OPT.encode_timestamp {
+            total & !0x7E == 128
+        } else {
+            total == 128
+        }
+    }
+
+    /// Find the initial TTL, of probes in this phase, matching the checksum mac.
+    fn recover_ttl(&self, mac: u16, destination: Ipv4Addr) -> Option<u8> {
+        (1..=32).find(|&ttl| {
+            OPT.probe_key
+                .checksum_mac(destination, ttl, self.phase, OPT.salt)
+                == mac
+        })
+    }
 }
 
 #[cfg(test)]
@@ -192,6 +532,190 @@ mod test {
         // println!("{:#?}", packet);
     }
 
+    /// Time exceeded to `IP2`, quoting the first 28 bytes of `probe`.
+    fn reply(probe: &[u8]) -> Vec<u8> {
+        let mut reply = vec![0u8; 56];
+        let mut ip_packet = MutableIpv4Packet::new(&mut reply).unwrap();
+        ip_packet.set_version(4);
+        ip_packet.set_header_length(5);
+        ip_packet.set_total_length(56);
+        ip_packet.set_ttl(60);
+        ip_packet.set_source("10.0.0.1".parse().unwrap());
+        ip_packet.set_destination(*IP2);
+        reply[20] = IcmpTypes::TimeExceeded.0;
+        reply[28..].copy_from_slice(&probe[..28]);
+        reply
+    }
+
+    #[test]
+    fn test_auth() {
+        let prober = Prober::new(ProbePhase::Main);
+        let mut probe = [0u8; Prober::PACK_BUFFER_LENGTH];
+        let len = prober.pack((*IP1, 5), *IP2, &mut probe);
+
+        // the checksum is still valid
+        let udp_packet = UdpPacket::new(&probe[20..len]).unwrap();
+        assert_eq!(
+            ipv4_checksum(&udp_packet, &IP2, &IP1),
+            udp_packet.get_checksum()
+        );
+
+        let result = prober.parse(&reply(&probe), false).unwrap();
+        assert_eq!(result.destination, *IP1);
+        assert_eq!(result.distance, 5);
+        assert_eq!(result.reply_ttl, 60);
+        assert_eq!(result.quoted_ttl, Some(5));
+        assert_eq!(result.icmp_type, Some(IcmpTypes::TimeExceeded.0));
+        assert!(result.rewrites.is_empty());
+
+        // ttl in the ip id is rewritten
+        let mut forged = probe;
+        forged[5] ^= 0x1;
+        assert!(matches!(
+            prober.parse(&reply(&forged), false),
+            Err(Error::UnexpectedProbeMac(..))
+        ));
+        assert!(prober.parse(&reply(&forged), true).is_ok());
+
+        let mut forged = probe;
+        forged[26] ^= 0x1;
+        assert!(matches!(
+            prober.parse(&reply(&forged), false),
+            Err(Error::UnexpectedProbeMac(..))
+        ));
+
+        // a stale reply to a preprobe
+        let preprober = Prober::new(ProbePhase::Pre);
+        let mut preprobe = [0u8; Prober::PACK_BUFFER_LENGTH];
+        preprober.pack((*IP1, 5), *IP2, &mut preprobe);
+        assert_eq!(
+            Prober::quoted_phase(&reply(&preprobe)),
+            Some(ProbePhase::Pre)
+        );
+        assert_eq!(Prober::quoted_phase(&reply(&probe)), Some(ProbePhase::Main));
+        assert!(preprober.parse(&reply(&preprobe), false).is_ok());
+        assert!(matches!(
+            prober.parse(&reply(&preprobe), false),
+            Err(Error::UnexpectedProbePhase(
+                ProbePhase::Pre,
+                ProbePhase::Main
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let prober = Prober::new(ProbePhase::Main);
+        let packet = TLE_WITH_DATA.packet();
+        for len in 24..28 {
+            assert!(matches!(
+                prober.parse(&packet[..len], true),
+                Err(Error::ParseError(3))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_udp_length() {
+        assert!(Prober::is_valid_udp_length(108));
+        assert!(!Prober::is_valid_udp_length(u16::MAX));
+    }
+
+    #[test]
+    fn test_rewrites() {
+        let prober = Prober::new(ProbePhase::Main);
+        let recovering = Prober {
+            phase: ProbePhase::Main,
+            recover_rewrites: true,
+        };
+        let mut probe = [0u8; Prober::PACK_BUFFER_LENGTH];
+        prober.pack((*IP1, 5), *IP2, &mut probe);
+        let port = u16::from_be_bytes([probe[20], probe[21]]);
+        let sum = u16::from_be_bytes([probe[26], probe[27]]);
+
+        // nat, with the checksum fixed up
+        let mut natted = probe;
+        let fixed = undo_rewrites(sum, &[(0x0403, 0xc0a8), (0x0201, 0x0001)]);
+        natted[12..16].copy_from_slice(&[192, 168, 0, 1]);
+        natted[26..28].copy_from_slice(&fixed.to_be_bytes());
+        let result = prober.parse(&reply(&natted), false).unwrap();
+        assert_eq!(result.rewrites, Rewrites(Rewrites::SRC_ADDR));
+        // unauthenticated replies have no rewrites
+        let result = prober.parse(&reply(&natted), true).unwrap();
+        assert!(result.rewrites.is_empty());
+
+        // napt, only with `--recover-rewrites`
+        let mut napted = probe;
+        let fixed = undo_rewrites(sum, &[(0x0403, 0xc0a8), (0x0201, 0x0001), (port, 1024)]);
+        napted[12..16].copy_from_slice(&[192, 168, 0, 1]);
+        napted[20..22].copy_from_slice(&1024u16.to_be_bytes());
+        napted[26..28].copy_from_slice(&fixed.to_be_bytes());
+        assert!(matches!(
+            prober.parse(&reply(&napted), false),
+            Err(Error::UnexpectedIcmpSrcPort(..))
+        ));
+        let result = recovering.parse(&reply(&napted), false).unwrap();
+        assert_eq!(
+            result.rewrites,
+            Rewrites(Rewrites::SRC_ADDR | Rewrites::SRC_PORT)
+        );
+        assert_eq!(result.rewrites.to_string(), "src_addr,src_port");
+
+        // a rewritten port alone is forged
+        let mut forged = probe;
+        forged[20..22].copy_from_slice(&1024u16.to_be_bytes());
+        assert!(matches!(
+            recovering.parse(&reply(&forged), false),
+            Err(Error::UnexpectedIcmpSrcPort(..))
+        ));
+
+        // ttl in the ip id is lost, but recovered by the mac
+        let mut rewritten = probe;
+        rewritten[4..6].copy_from_slice(&[0x12, 0x34]);
+        assert!(matches!(
+            prober.parse(&reply(&rewritten), false),
+            Err(Error::UnexpectedProbeMac(..))
+        ));
+        let result = recovering.parse(&reply(&rewritten), false).unwrap();
+        assert_eq!(result.rewrites, Rewrites(Rewrites::IP_ID));
+        assert_eq!(result.distance, 5);
+        assert_eq!(result.debug.rtt, 0);
+        // never without the port mac
+        let mut rewritten = napted;
+        rewritten[4..6].copy_from_slice(&[0x12, 0x34]);
+        assert!(matches!(
+            recovering.parse(&reply(&rewritten), false),
+            Err(Error::UnexpectedProbeMac(..))
+        ));
+
+        // checksum cleared
+        let mut cleared = probe;
+        cleared[26..28].copy_from_slice(&[0, 0]);
+        assert!(matches!(
+            prober.parse(&reply(&cleared), false),
+            Err(Error::UnexpectedProbeMac(..))
+        ));
+        let result = recovering.parse(&reply(&cleared), false).unwrap();
+        assert_eq!(result.rewrites, Rewrites(Rewrites::CHECKSUM));
+
+        assert_eq!(
+            "ip_id,checksum".parse::<Rewrites>().unwrap(),
+            Rewrites(Rewrites::IP_ID | Rewrites::CHECKSUM)
+        );
+        assert_eq!("-".parse::<Rewrites>().unwrap(), Rewrites::default());
+    }
+
+    #[test]
+    fn test_probe_key() {
+        let key: ProbeKey = "000102030405060708090a0b0c0d0e0f".parse().unwrap();
+        assert_eq!(key, ProbeKey(0x0001020304050607, 0x08090a0b0c0d0e0f));
+        assert_eq!(key.to_string(), "000102030405060708090a0b0c0d0e0f");
+        assert!("0011".parse::<ProbeKey>().is_err());
+        assert_ne!(key.port_mac(*IP1, 0), ProbeKey::default().port_mac(*IP1, 0));
+        assert_eq!(key.fingerprint().len(), 16);
+        assert_ne!(key.fingerprint(), ProbeKey::default().fingerprint());
+    }
+
     #[test]
     fn test_parse() {
         let prober = Prober::new(ProbePhase::Pre);
@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::Ipv4Addr,
+    path::Path,
+};
+
+use crate::{
+    error::*,
+    prober::{ProbeDebugResult, ProbeResult, Rewrites},
+};
+
+/// Raw results of a scan, i.e., replies of the main phase grouped by destination.
+///
+/// On disk, the vantage address comes first in a `#` comment, followed by a header line and
+/// one reply per line with fields separated by tabs, where unknown ones are written as `-`.
+/// Results merged from multiple vantage points have a section for each, starting with its own
+/// `#` comment.
+#[derive(Debug)]
+pub struct Results {
+    pub vantage: Ipv4Addr,
+    /// Destinations probed from other vantage points than `vantage`, after merging.
+    pub other_vantages: HashMap<Ipv4Addr, Ipv4Addr>,
+    pub by_destination: HashMap<Ipv4Addr, Vec<ProbeResult>>,
+}
+
+impl Results {
+    const HEADER: &'static str =
+        "destination\tresponder\tdistance\tfrom_destination\treply_ttl\trtt\t\
+                                  rewrites\tquoted_ttl\ticmp_type\ticmp_code";
+
+    pub fn new(vantage: Ipv4Addr) -> Self {
+        Self {
+            vantage,
+            other_vantages: HashMap::new(),
+            by_destination: HashMap::new(),
+        }
+    }
+
+    pub fn vantage_of(&self, destination: Ipv4Addr) -> Ipv4Addr {
+        self.other_vantages
+            .get(&destination)
+            .cloned()
+            .unwrap_or(self.vantage)
+    }
+
+    /// All vantage points, with `vantage` first and others in order.
+    pub fn vantages(&self) -> Vec<Ipv4Addr> {
+        let mut others: Vec<_> = self
+            .other_vantages
+            .values()
+            .filter(|&&v| v != self.vantage)
+            .cloned()
+            .collect();
+        others.sort_unstable();
+        others.dedup();
+        std::iter::once(self.vantage).chain(others).collect()
+    }
+
+    /// Take replies of `other`, remembering its vantage point for each destination.
+    pub fn merge(&mut self, other: Results) {
+        let Results {
+            vantage,
+            other_vantages,
+            by_destination,
+        } = other;
+        for (destination, results) in by_destination {
+            let vantage = other_vantages.get(&destination).cloned().unwrap_or(vantage);
+            if vantage != self.vantage {
+                self.other_vantages.insert(destination, vantage);
+            }
+            self.by_destination
+                .entry(destination)
+                .or_default()
+                .extend(results);
+        }
+    }
+
+    pub fn push(&mut self, result: ProbeResult) {
+        self.by_destination
+            .entry(result.destination)
+            .or_default()
+            .push(result);
+    }
+
+    pub fn reply_count(&self) -> usize {
+        self.by_destination.values().map(|v| v.len()).sum()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for (i, vantage) in self.vantages().into_iter().enumerate() {
+            writeln!(file, "# vantage {}", vantage)?;
+            if i == 0 {
+                writeln!(file, "{}", Self::HEADER)?;
+            }
+            for (&destination, results) in &self.by_destination {
+                if self.vantage_of(destination) != vantage {
+                    continue;
+                }
+                for r in results {
+                    writeln!(file, "{}", Self::format_line(r))?;
+                }
+            }
+        }
+        file.flush()?;
+
+        Ok(())
+    }
+
+    pub fn format_line(r: &ProbeResult) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            r.destination,
+            r.responder,
+            r.distance,
+            r.from_destination as u8,
+            r.reply_ttl,
+            r.debug.rtt,
+            r.rewrites,
+            format_unknown(r.quoted_ttl),
+            format_unknown(r.icmp_type),
+            format_unknown(r.icmp_code)
+        )
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut results = Self::new(Ipv4Addr::UNSPECIFIED);
+        // vantage point of the current section
+        let mut vantage = None;
+
+        for line in file.lines() {
+            let line = line?;
+            if let Some(comment) = line.strip_prefix('#') {
+                let mut fields = comment.split_whitespace();
+                if let (Some("vantage"), Some(addr)) = (fields.next(), fields.next()) {
+                    let addr = addr
+                        .parse()
+                        .or(Err(Error::InvalidResultLine(line.clone())))?;
+                    if vantage.is_none() {
+                        results.vantage = addr;
+                    }
+                    vantage = Some(addr);
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with("destination\t") {
+                continue;
+            }
+            let result = Self::parse_line(&line).ok_or(Error::InvalidResultLine(line))?;
+            match vantage {
+                Some(v) if v != results.vantage => {
+                    results.other_vantages.insert(result.destination, v);
+                }
+                _ => {}
+            }
+            results.push(result);
+        }
+
+        Ok(results)
+    }
+
+    pub fn parse_line(line: &str) -> Option<ProbeResult> {
+        let mut fields = line.split('\t');
+        let mut next = || fields.next();
+        let destination = next()?.parse().ok()?;
+        let responder = next()?.parse().ok()?;
+        let distance = next()?.parse().ok()?;
+        let from_destination = next()? == "1";
+        let reply_ttl = next()?.parse().ok()?;
+        let rtt = next()?.parse().ok()?;
+        let rewrites = next()?.parse().ok()?;
+        let quoted_ttl = parse_unknown(next()?)?;
+        let icmp_type = parse_unknown(next()?)?;
+        let icmp_code = parse_unknown(next()?)?;
+        Some(ProbeResult {
+            destination,
+            responder,
+            distance,
+            from_destination,
+            distance_inferred: false,
+            reply_ttl,
+            quoted_ttl,
+            icmp_type,
+            icmp_code,
+            rewrites,
+            debug: ProbeDebugResult { rtt },
+        })
+    }
+
+    /// Responders right after rewrites of probes, with fields newly rewritten since the
+    /// previous hop of any trace, i.e., the middlebox is in between.
+    pub fn rewrite_hops(&self) -> HashMap<Ipv4Addr, Rewrites> {
+        let mut hops = HashMap::<Ipv4Addr, Rewrites>::new();
+        for replies in self.by_destination.values() {
+            let mut replies: Vec<_> = replies.iter().collect();
+            replies.sort_by_key(|r| r.distance);
+            let mut seen = Rewrites::default();
+            for r in replies {
+                let new = Rewrites(r.rewrites.0 & !seen.0);
+                if !new.is_empty() {
+                    hops.entry(r.responder).or_default().insert(new.0);
+                }
+                seen.insert(r.rewrites.0);
+            }
+        }
+        hops
+    }
+}
+
+/// `-` if unknown.
+fn format_unknown(value: Option<u8>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+fn parse_unknown(field: &str) -> Option<Option<u8>> {
+    match field {
+        "-" => Some(None),
+        _ => field.parse().ok().map(Some),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_read() {
+        let mut results = Results::new("10.0.0.1".parse().unwrap());
+        results.push(ProbeResult {
+            reply_ttl: 252,
+            quoted_ttl: Some(2),
+            icmp_code: None,
+            rewrites: Rewrites(Rewrites::SRC_ADDR),
+            ..ProbeResult::for_test("1.2.3.4", "5.6.7.8", 3, 42)
+        });
+
+        let path = crate::utils::test_path("write_read.results");
+        results.write(&path).unwrap();
+        let read = Results::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.vantage, results.vantage);
+        assert_eq!(read.reply_count(), 1);
+        let r = &read.by_destination[&"1.2.3.4".parse().unwrap()][0];
+        assert_eq!(r.responder, "5.6.7.8".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(r.distance, 3);
+        assert!(!r.from_destination);
+        assert_eq!(r.reply_ttl, 252);
+        assert_eq!(r.debug.rtt, 42);
+        assert_eq!(r.rewrites, Rewrites(Rewrites::SRC_ADDR));
+        assert_eq!(r.quoted_ttl, Some(2));
+        assert_eq!((r.icmp_type, r.icmp_code), (Some(11), None));
+    }
+
+    #[test]
+    fn test_rewrite_hops() {
+        let result = |responder: &str, distance: u8, rewrites: u8| ProbeResult {
+            rewrites: Rewrites(rewrites),
+            ..ProbeResult::for_test("1.2.3.4", responder, distance, 42)
+        };
+        let mut results = Results::new("10.0.0.1".parse().unwrap());
+        results.push(result("10.0.0.254", 1, 0));
+        results.push(result("5.6.7.9", 3, Rewrites::SRC_ADDR));
+        results.push(result("5.6.7.8", 2, Rewrites::SRC_ADDR));
+        results.push(result("5.6.7.10", 4, Rewrites::SRC_ADDR | Rewrites::IP_ID));
+
+        let hops = results.rewrite_hops();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(
+            hops[&"5.6.7.8".parse().unwrap()],
+            Rewrites(Rewrites::SRC_ADDR)
+        );
+        assert_eq!(
+            hops[&"5.6.7.10".parse().unwrap()],
+            Rewrites(Rewrites::IP_ID)
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let result = |destination: &str| ProbeResult::for_test(destination, "5.6.7.8", 3, 42);
+        let mut results = Results::new("10.0.0.1".parse().unwrap());
+        results.push(result("1.2.3.4"));
+        let mut other = Results::new("10.0.0.2".parse().unwrap());
+        other.push(result("1.2.3.5"));
+        results.merge(other);
+
+        let path = crate::utils::test_path("merge.results");
+        results.write(&path).unwrap();
+        let read = Results::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.reply_count(), 2);
+        assert_eq!(
+            read.vantages(),
+            vec![
+                "10.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            read.vantage_of("1.2.3.5".parse().unwrap()),
+            "10.0.0.2".parse::<Ipv4Addr>().unwrap()
+        );
+        assert_eq!(read.vantage_of("1.2.3.4".parse().unwrap()), read.vantage);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    net::Ipv4Addr,
+    path::Path,
+};
+
+use flate2::read::MultiGzDecoder;
+use ipnet::Ipv4Net;
+use rand::rngs::StdRng;
+
+use crate::{error::*, filter};
+
+/// Targets read from a list file. Each line is one of
+/// - an address, e.g., `1.2.3.4`,
+/// - a network, e.g., `1.2.3.0/24`, from which a random host per grain-sized subnet is picked,
+/// - a range, e.g., `1.2.3.4-1.2.5.6`, from which a random host per grain-sized subnet overlapping
+///   it is picked, within the range,
+///
+/// optionally followed by a `#` comment. The file may be gzip-compressed, and `-` is stdin.
+#[derive(Debug, Default)]
+pub struct TargetList {
+    pub addrs: Vec<Ipv4Addr>,
+    pub invalid_lines: usize,
+}
+
+impl TargetList {
+    pub fn read(path: &Path, grain: u8, lenient: bool, rng: &mut StdRng) -> Result<Self> {
+        let mut list = Self::default();
+        for (i, line) in Self::open(path)?.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Err(e) = list.parse_line(line, grain, rng) {
+                if lenient {
+                    log::warn!("skipped line {} of target list: {}", i + 1, e);
+                    list.invalid_lines += 1;
+                } else {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+        let reader: Box<dyn Read> = if path == Path::new("-") {
+            Box::new(std::io::stdin())
+        } else {
+            Box::new(File::open(path)?)
+        };
+        let mut reader = BufReader::new(reader);
+        let gzipped = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        if gzipped {
+            Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+
+    fn parse_line(&mut self, line: &str, grain: u8, rng: &mut StdRng) -> Result<()> {
+        let invalid = || Error::InvalidIpv4Addr(line.to_owned());
+
+        if let Ok(addr) = line.parse::<Ipv4Addr>() {
+            self.addrs.push(addr);
+        } else if let Ok(net) = line.parse::<Ipv4Net>() {
+            self.push_net(net.trunc(), grain, rng);
+        } else if let Some(pos) = line.find('-') {
+            let start: Ipv4Addr = line[..pos].trim().parse().or(Err(invalid()))?;
+            let end: Ipv4Addr = line[pos + 1..].trim().parse().or(Err(invalid()))?;
+            if start > end {
+                return Err(invalid());
+            }
+            self.push_range(start, end, grain, rng);
+        } else {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    fn push_range(&mut self, start: Ipv4Addr, end: Ipv4Addr, grain: u8, rng: &mut StdRng) {
+        let (start, end) = (u32::from(start) as u64, u32::from(end) as u64);
+        // one host per grain, even if the range splits it into multiple networks
+        for subnet in start >> grain..=end >> grain {
+            let low = (subnet << grain).max(start);
+            let high = ((subnet + 1) << grain).min(end + 1);
+            self.addrs.push(filter::pick_permitted(low, high, rng));
+        }
+    }
+
+    fn push_net(&mut self, net: Ipv4Net, grain: u8, rng: &mut StdRng) {
+        let host_bits = net.max_prefix_len() - net.prefix_len();
+        if grain >= host_bits {
+            // smaller than a grain, pick a single host
+            let low = u32::from(net.addr()) as u64;
+            self.addrs
+                .push(filter::pick_permitted(low, low + (1 << host_bits), rng));
+        } else {
+            for subnet in net.subnets(net.max_prefix_len() - grain).unwrap() {
+                let low = u32::from(subnet.addr()) as u64;
+                self.addrs
+                    .push(filter::pick_permitted(low, low + (1 << grain), rng));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn parse(content: &str, grain: u8) -> Result<TargetList> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut list = TargetList::default();
+        for line in content.lines() {
+            list.parse_line(line, grain, &mut rng)?;
+        }
+        Ok(list)
+    }
+
+    #[test]
+    fn test_parse() {
+        let list = parse("1.2.3.4\n10.0.0.0/22\n10.1.0.0-10.1.2.255", 8).unwrap();
+        assert_eq!(list.addrs.len(), 1 + 4 + 3);
+        assert_eq!(list.addrs[0], "1.2.3.4".parse::<Ipv4Addr>().unwrap());
+        let net: Ipv4Net = "10.0.0.0/22".parse().unwrap();
+        assert!(list.addrs[1..5].iter().all(|addr| net.contains(addr)));
+        let net: Ipv4Net = "10.1.0.0/22".parse().unwrap();
+        assert!(list.addrs[5..].iter().all(|addr| net.contains(addr)));
+
+        // not aligned to grains
+        let list = parse("10.1.0.200-10.1.2.10", 8).unwrap();
+        assert_eq!(list.addrs.len(), 3);
+        let (start, end): (Ipv4Addr, Ipv4Addr) =
+            ("10.1.0.200".parse().unwrap(), "10.1.2.10".parse().unwrap());
+        assert!(list.addrs.iter().all(|&addr| start <= addr && addr <= end));
+        let mut subnets: Vec<_> = list.addrs.iter().map(|&a| u32::from(a) >> 8).collect();
+        subnets.dedup();
+        assert_eq!(subnets.len(), 3);
+
+        let list = parse("10.0.0.0/30", 8).unwrap();
+        assert_eq!(list.addrs.len(), 1);
+        let list = parse("10.0.0.0/30", 0).unwrap();
+        assert_eq!(list.addrs.len(), 4);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse("1.2.3", 8).is_err());
+        assert!(parse("1.2.3.4/33", 8).is_err());
+        assert!(parse("1.2.3.4-1.2.3.3", 8).is_err());
+    }
+}
@@ -1,11 +1,14 @@
-use std::{collections::HashMap, net::Ipv4Addr};
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
 
 use petgraph::{dot::Dot, graphmap::UnGraphMap};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
 
 use crate::error::*;
-use crate::{prober::ProbeDebugResult, prober::ProbeResult, OPT};
+use crate::{opt::OutputOpt, prober::ProbeResult, results::Results};
 
 type MpscTx<T> = mpsc::UnboundedSender<T>;
 type MpscRx<T> = mpsc::UnboundedReceiver<T>;
@@ -19,37 +22,61 @@ pub enum TopoReq {
 
 pub struct Topo {
     req_rx: MpscRx<TopoReq>,
-    results_buf: HashMap<Ipv4Addr, Vec<ProbeResult>>,
-    graph: TopoGraph,
+    results: Results,
+    results_path: Option<PathBuf>,
 }
 
 impl Topo {
-    pub fn new(req_rx: MpscRx<TopoReq>) -> Self {
-        let mut graph = UnGraphMap::new();
-        graph.add_node(OPT.local_addr);
+    pub fn new(
+        req_rx: MpscRx<TopoReq>,
+        local_addr: Ipv4Addr,
+        results_path: Option<PathBuf>,
+    ) -> Self {
         Self {
             req_rx,
-            results_buf: HashMap::new(),
-            graph,
+            results: Results::new(local_addr),
+            results_path,
+        }
+    }
+
+    /// Collect replies until stopped, then save them if `results_path` is set.
+    pub async fn run(mut self) -> Results {
+        while let Some(req) = self.req_rx.recv().await {
+            match req {
+                TopoReq::Result(result) => {
+                    self.results.push(result);
+                }
+                TopoReq::Stop => {
+                    break;
+                }
+            }
         }
+
+        if let Some(path) = &self.results_path {
+            log::info!("Saving raw results to {}...", path.display());
+            if let Err(e) = self.results.write(path) {
+                log::error!("failed to save raw results: {}", e);
+            }
+        }
+
+        self.results
     }
 
-    pub async fn run(mut self) -> TopoGraph {
-        let local = ProbeResult {
-            destination: OPT.local_addr,
-            responder: OPT.local_addr,
-            distance: 0,
-            from_destination: true,
-            debug: ProbeDebugResult::default(),
-        };
+    pub fn build_graph(results: &Results) -> TopoGraph {
+        let mut graph = UnGraphMap::new();
+        for vantage in results.vantages() {
+            graph.add_node(vantage);
+        }
 
-        let process = |mut results: Vec<ProbeResult>, graph: &mut TopoGraph| {
+        for (&destination, replies) in &results.by_destination {
+            let local = results.vantage_of(destination);
+            let mut results: Vec<_> = replies.iter().collect();
             results.sort_by_key(|r| r.distance);
             if let Some(first) = results.first() {
                 let dist = first.distance;
                 graph.add_node(first.responder);
                 if dist <= 1 {
-                    graph.add_edge(local.responder, first.responder, dist);
+                    graph.add_edge(local, first.responder, dist);
                 }
             }
             for (a, b) in results.iter().zip(results.iter().skip(1)) {
@@ -59,37 +86,36 @@ impl Topo {
                     graph.add_edge(a.responder, b.responder, dist);
                 }
             }
-        };
-
-        while let Some(req) = self.req_rx.recv().await {
-            match req {
-                TopoReq::Result(result) => {
-                    self.results_buf
-                        .entry(result.destination)
-                        .or_insert(Vec::new())
-                        .push(result);
-                }
-                TopoReq::Stop => {
-                    for (_, results) in self.results_buf {
-                        process(results, &mut self.graph);
-                    }
-                    break;
-                }
-            }
         }
 
-        self.graph
+        graph
     }
 
-    pub async fn process_graph(topo_graph: TopoGraph) -> Result<()> {
+    /// Render the topology of `results`, with vantage points as boxes and responders right after
+    /// rewrites of probes in red.
+    pub async fn process_graph(
+        results: &Results,
+        output: &OutputOpt,
+        partial: bool,
+        comment: &str,
+    ) -> Result<()> {
+        let topo_graph = Self::build_graph(results);
+        let vantages = results.vantages();
+        let rewrite_hops = results.rewrite_hops();
         log::info!("[Summary] Total probed hosts: {}", topo_graph.node_count());
+        if !rewrite_hops.is_empty() {
+            log::info!(
+                "[Summary] Rewrite hops (NATs or middleboxes): {}",
+                rewrite_hops.len()
+            );
+        }
 
-        if OPT.dot {
+        if output.dot {
             let dot_content =
                 Dot::with_config(&topo_graph, &[petgraph::dot::Config::GraphContentOnly]);
 
-            let dot_path = OPT.output_dot.to_str().unwrap();
-            let viz_path = OPT.output_viz.to_str().unwrap();
+            let dot_path = output.output_dot.to_str().unwrap();
+            let viz_path = output.output_viz.to_str().unwrap();
             let mut dot_file = tokio::fs::File::create(dot_path).await?;
 
             macro_rules! write {
@@ -100,30 +126,53 @@ impl Topo {
 
             log::info!("Saving topology to {}...", dot_path);
             write!("graph {\n    overlap = false;\n");
-            if OPT.spline {
+            if partial {
+                write!("    comment = \"partial\";\n");
+            }
+            write!(format!("    // {}\n", comment));
+            if output.spline {
                 write!("    splines = true;\n");
             }
             for s in format!("{}", dot_content).lines() {
                 write!(s);
                 write!("\n");
             }
+            // nodes are numbered in order by `Dot`
+            for (i, node) in topo_graph.nodes().enumerate() {
+                if vantages.contains(&node) {
+                    let line = format!("    {} [ shape = box, comment = \"vantage\" ]\n", i);
+                    dot_file.write_all(line.as_bytes()).await?;
+                }
+                if let Some(rewrites) = rewrite_hops.get(&node) {
+                    let line = format!(
+                        "    {} [ color = red, comment = \"rewrite: {}\" ]\n",
+                        i, rewrites
+                    );
+                    dot_file.write_all(line.as_bytes()).await?;
+                }
+            }
             write!("}\n");
 
-            if OPT.plot {
-                log::info!("Plotting to {}...", viz_path);
-                tokio::process::Command::new("dot")
-                    .arg("-K")
-                    .arg(OPT.layout.as_str())
-                    .arg("-Tpng")
-                    .arg(dot_path)
-                    .arg("-o")
-                    .arg(viz_path)
-                    .spawn()?
-                    .wait()
-                    .await?;
+            if output.plot {
+                Self::plot(dot_path.as_ref(), viz_path.as_ref(), &output.layout).await?;
             }
         }
 
         Ok(())
     }
+
+    pub async fn plot(dot_path: &Path, viz_path: &Path, layout: &str) -> Result<()> {
+        log::info!("Plotting to {}...", viz_path.display());
+        tokio::process::Command::new("dot")
+            .arg("-K")
+            .arg(layout)
+            .arg("-Tpng")
+            .arg(dot_path)
+            .arg("-o")
+            .arg(viz_path)
+            .spawn()?
+            .wait()
+            .await?;
+        Ok(())
+    }
 }
@@ -1,29 +1,40 @@
 use std::{
     io::Write,
     net::Ipv4Addr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use hashbrown::{hash_map::HashMap, hash_set::HashSet};
-use ipnet::IpAdd;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::Ipv4Packet};
+use rand::{rngs::StdRng, SeedableRng};
 use tokio::sync::{mpsc, oneshot};
 use Ordering::SeqCst;
 
 use crate::{
-    dcb::DstCtrlBlock,
+    cluster::{self, SharedDistance, Worker},
+    control::{Stage, CONTROL},
+    dcb::{BackwardStopReason, Confidence, DstCtrlBlock},
     error::*,
+    filter,
+    hitlist::Hitlist,
+    metrics::METRICS,
     network::NetworkManager,
     opt::Targets,
+    pcap,
+    permutation::CyclicPermutation,
+    prefix::PrefixMap,
     prober::ProbePhase,
     prober::ProbeResult,
     prober::Prober,
-    topo::{Topo, TopoGraph, TopoReq},
+    results::Results,
+    target_list::TargetList,
+    topo::{Topo, TopoReq},
+    tui,
     utils::GlobalIpv4Ext,
     OPT,
 };
@@ -34,12 +45,59 @@ type MpscRx<T> = mpsc::UnboundedReceiver<T>;
 type AddrKey = i64;
 type DcbMap = HashMap<AddrKey, DstCtrlBlock>;
 
+/// How split ttls predicted in preprobing compare to distances observed in the main phase.
+#[derive(Debug, Default)]
+struct PredictionStats {
+    observed: HashSet<AddrKey>,
+    // (count, exact count, sum of absolute errors)
+    by_confidence: HashMap<Confidence, (u64, u64, u64)>,
+}
+
+impl PredictionStats {
+    fn observe(&mut self, key: AddrKey, dcb: &DstCtrlBlock, distance: u8) {
+        if !self.observed.insert(key) {
+            return;
+        }
+        let predicted = dcb.initial_ttl();
+        let error = (predicted.max(distance) - predicted.min(distance)) as u64;
+        let entry = self
+            .by_confidence
+            .entry(dcb.confidence())
+            .or_insert((0, 0, 0));
+        entry.0 += 1;
+        entry.1 += (error == 0) as u64;
+        entry.2 += error;
+    }
+
+    fn report(&self) {
+        let mut by_confidence: Vec<_> = self.by_confidence.iter().collect();
+        by_confidence.sort_by_key(|(&c, _)| std::cmp::Reverse(c));
+        for (confidence, &(count, exact, error_sum)) in by_confidence {
+            log::info!(
+                "[Summary] Split TTL ({:?}): observed {}, exact {:.1}%, mean error {:.2} hops",
+                confidence,
+                count,
+                exact as f64 * 100.0 / count as f64,
+                error_sum as f64 / count as f64,
+            );
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Tracerouter {
     targets: Arc<DcbMap>,
     stopped: Arc<AtomicBool>,
+    budget_exceeded: AtomicBool,
+    results_path: Option<PathBuf>,
+    // split ttls from the previous cycle, with which preprobing is skipped
+    known_distances: Option<HashMap<AddrKey, (u8, Confidence)>>,
+    worker: Option<Arc<Worker>>,
 
     // stats
+    invalid_target_lines: usize,
+    start_time_ms: AtomicU64,
+    end_time_ms: AtomicU64,
     sent_preprobes: AtomicU64,
     sent_probes: AtomicU64,
     recv_responses_pre: AtomicU64,
@@ -52,6 +110,24 @@ pub struct Tracerouter {
 
 impl Tracerouter {
     pub fn new() -> Result<Self> {
+        let (targets, invalid_target_lines) = Self::init_targets()?;
+        Ok(Self::with_targets(targets, invalid_target_lines))
+    }
+
+    /// Join the coordinator at `spec`, and probe only the share of targets it assigns.
+    pub async fn join(spec: &str) -> Result<Self> {
+        let (mut targets, invalid_target_lines) = Self::init_targets()?;
+        let fingerprint = cluster::fingerprint(targets.keys().cloned());
+        let worker = Worker::connect(spec, fingerprint).await?;
+        targets.retain(|&key, _| worker.owns(key));
+        log::info!("[Worker] Probing {} targets of the share", targets.len());
+
+        let mut tr = Self::with_targets(targets, invalid_target_lines);
+        tr.worker = Some(Arc::new(worker));
+        Ok(tr)
+    }
+
+    fn init_targets() -> Result<(DcbMap, usize)> {
         log::info!(
             "Using interface `{}` ({})",
             OPT.interface.name,
@@ -59,17 +135,58 @@ impl Tracerouter {
         );
 
         log::info!("Initializing targets...");
-        let targets = Self::generate_targets()?;
+        let (targets, invalid_target_lines) = Self::generate_targets()?;
 
         if let Some(path) = OPT.dump_targets.clone() {
             log::info!("Dumping targets...");
             Self::dump_targets(&targets, &path)?;
         }
+        Ok((targets, invalid_target_lines))
+    }
+
+    fn with_targets(targets: DcbMap, invalid_target_lines: usize) -> Self {
+        let results_path = if OPT.results {
+            Some(OPT.output_results.clone())
+        } else {
+            None
+        };
 
-        Ok(Self {
+        Self {
             targets: Arc::new(targets),
+            results_path,
+            invalid_target_lines,
             ..Self::default()
-        })
+        }
+    }
+
+    pub fn with_results_path(mut self, results_path: Option<PathBuf>) -> Self {
+        self.results_path = results_path;
+        self
+    }
+
+    /// A tracerouter for the next cycle of monitoring, which probes the same targets but skips
+    /// preprobing by reusing split ttls of this one. It's stopped along with this one.
+    pub fn next_cycle(&self, results_path: Option<PathBuf>) -> Self {
+        let targets = self
+            .targets
+            .iter()
+            .map(|(&key, dcb)| (key, DstCtrlBlock::new(dcb.addr, OPT.split_ttl)))
+            .collect();
+        let known_distances = self
+            .targets
+            .iter()
+            .filter(|(_, dcb)| dcb.confidence() != Confidence::None)
+            .map(|(&key, dcb)| (key, (dcb.initial_ttl(), dcb.confidence())))
+            .collect();
+
+        Self {
+            targets: Arc::new(targets),
+            stopped: self.stopped.clone(),
+            results_path,
+            known_distances: Some(known_distances),
+            invalid_target_lines: self.invalid_target_lines,
+            ..Self::default()
+        }
     }
 
     fn addr_to_key(addr: Ipv4Addr) -> AddrKey {
@@ -77,18 +194,63 @@ impl Tracerouter {
         (u >> (OPT.grain)) as AddrKey
     }
 
-    fn generate_targets() -> Result<DcbMap> {
+    /// The first address of the block of `key`.
+    fn key_to_addr(key: AddrKey) -> Ipv4Addr {
+        Ipv4Addr::from(((key as u64) << OPT.grain) as u32)
+    }
+
+    /// Sort keys for determinism, then shuffle them by a cyclic permutation if enabled, so that
+    /// probes towards the same network are spread out.
+    fn permute_keys(mut keys: Vec<AddrKey>, seed: u64) -> Vec<AddrKey> {
+        keys.sort_unstable();
+        if !OPT.shuffle {
+            return keys;
+        }
+        CyclicPermutation::new(keys.len(), seed)
+            .map(|i| keys[i])
+            .collect()
+    }
+
+    fn generate_targets() -> Result<(DcbMap, usize)> {
         match OPT.targets.clone() {
             Targets::Net(net) => {
                 if OPT.grain > (net.max_prefix_len() - net.prefix_len()) {
                     return Err(Error::BadGrainOrNet(OPT.grain, net));
                 }
 
+                let hitlist = match &OPT.hitlist {
+                    Some(path) => {
+                        let hitlist = Hitlist::from_file(path, OPT.grain)?;
+                        log::info!(
+                            "Loaded {} responsive blocks from hitlist",
+                            hitlist.block_count()
+                        );
+                        Some(hitlist)
+                    }
+                    None => None,
+                };
+                let mut hit_count = 0usize;
+
                 let mut rng = StdRng::seed_from_u64(OPT.seed);
                 let subnets = net.subnets(net.max_prefix_len() - OPT.grain).unwrap();
 
                 let iter = subnets
-                    .map(move |net| net.addr().saturating_add(rng.gen_range(0, 1 << OPT.grain)))
+                    .map(|net| {
+                        let low = u32::from(net.addr()) as u64;
+                        let random_addr =
+                            filter::pick_permitted(low, low + (1 << OPT.grain), &mut rng);
+                        match hitlist
+                            .as_ref()
+                            .and_then(|h| h.best_in_block(net.addr()))
+                            .filter(|&addr| filter::is_permitted(addr))
+                        {
+                            Some(addr) => {
+                                hit_count += 1;
+                                addr
+                            }
+                            None => random_addr,
+                        }
+                    })
                     .filter(|addr| {
                         if OPT.global_only && OPT.allow_private {
                             addr.is_bz_global() || addr.is_private()
@@ -97,7 +259,8 @@ impl Tracerouter {
                         } else {
                             true
                         }
-                    });
+                    })
+                    .filter(|addr| filter::is_permitted(*addr));
 
                 let all_count = 1 << ((net.max_prefix_len() - net.prefix_len()) - OPT.grain);
                 let mut generated_targets = DcbMap::with_capacity(all_count);
@@ -113,29 +276,37 @@ impl Tracerouter {
                     filtered_count,
                     all_count - filtered_count
                 );
+                if hitlist.is_some() {
+                    log::info!("Selected {} targets from hitlist", hit_count);
+                }
 
-                Ok(generated_targets)
+                Ok((generated_targets, 0))
             }
 
             Targets::List(path) => {
                 let mut generated_targets = DcbMap::new();
+                let mut removed_count = 0usize;
 
-                let content = std::fs::read_to_string(path)?;
-                for line in content.lines() {
-                    if line.is_empty() {
+                let mut rng = StdRng::seed_from_u64(OPT.seed);
+                let list = TargetList::read(&path, OPT.grain, OPT.lenient, &mut rng)?;
+                for addr in list.addrs {
+                    if !filter::is_permitted(addr) {
+                        removed_count += 1;
                         continue;
                     }
-                    let addr = line
-                        .parse()
-                        .or(Err(Error::InvalidIpv4Addr(line.to_owned())))?;
                     generated_targets.insert(
                         Self::addr_to_key(addr),
                         DstCtrlBlock::new(addr, OPT.split_ttl),
                     );
                 }
-                log::info!("Imported {} targets from file", generated_targets.len());
+                log::info!(
+                    "Imported {} targets from file, {} removed, {} invalid lines skipped",
+                    generated_targets.len(),
+                    removed_count,
+                    list.invalid_lines
+                );
 
-                Ok(generated_targets)
+                Ok((generated_targets, list.invalid_lines))
             }
         }
     }
@@ -151,13 +322,110 @@ impl Tracerouter {
 }
 
 impl Tracerouter {
-    pub async fn run(&self) -> Result<TopoGraph> {
+    pub async fn run(&self) -> Result<Results> {
         let start_time = SystemTime::now();
 
-        let _ = self.run_preprobing_task().await?;
-        let topo = self.run_probing_task().await?;
+        match &self.known_distances {
+            Some(known) => self.reuse_distances(known),
+            None => self.run_preprobing_task(start_time).await?,
+        }
+        let topo = self.run_probing_task(start_time).await?;
+
+        CONTROL.set_stage(Stage::Done);
+        pcap::flush_capture();
+        self.summarize(start_time);
+
+        Ok(topo)
+    }
+
+    /// Rebuild the topology from replies in a capture instead of probing, where those annotated
+    /// with the preprobing phase by `--pcap-out` go through preprobing, and others through the
+    /// main phase. Replies captured without phases, e.g., by tcpdump, go by the phase of their
+    /// quoted probe.
+    pub async fn replay(&self, path: &Path) -> Result<Results> {
+        let start_time = SystemTime::now();
+
+        log::info!("[Replay] Reading {}...", path.display());
+        let (pre, main): (Vec<_>, Vec<_>) = pcap::read_capture(path)?
+            .into_iter()
+            .filter(|p| {
+                Ipv4Packet::new(&p.data)
+                    .map(|ip| ip.get_next_level_protocol() == IpNextHeaderProtocols::Icmp)
+                    .unwrap_or(false)
+            })
+            .partition(|p| {
+                let phase = p.phase.or_else(|| Prober::quoted_phase(&p.data));
+                matches!(phase, Some(ProbePhase::Pre))
+            });
+        log::info!(
+            "[Replay] ICMP packets: preprobing {}, probing {}",
+            pre.len(),
+            main.len()
+        );
 
+        // probe MACs are only verifiable with the secret of the original scan
+        let ignore_auth = OPT.probe_secret.is_none();
+        if ignore_auth {
+            log::warn!("[Replay] Without `--probe-secret`, replies are not authenticated");
+        }
+        let prober = Prober::new(ProbePhase::Pre);
+        for packet in &pre {
+            match prober.parse_at(&packet.data, ignore_auth, packet.time_ms as u16) {
+                Ok(result) => {
+                    self.recv_responses_pre.fetch_add(1, SeqCst);
+                    Self::preprobing_callback(&self.targets, result);
+                }
+                Err(e) => METRICS.on_recv_error(&e),
+            }
+        }
+        self.propagate_proximity(&[], OPT.prefixes.as_ref());
+        self.report_split_ttls();
+
+        // replies are destined to the vantage point of the capture
+        let vantage = main
+            .iter()
+            .chain(pre.iter())
+            .find_map(|p| Ipv4Packet::new(&p.data).map(|ip| ip.get_destination()))
+            .unwrap_or(OPT.local_addr);
+        let (topo_tx, topo_rx) = mpsc::unbounded_channel();
+        let topo = Topo::new(topo_rx, vantage, self.results_path.clone());
+
+        let mut backward_stop_set = HashMap::<Ipv4Addr, Ipv4Addr>::new();
+        let mut forward_discovery_set = HashSet::<Ipv4Addr>::new();
+        METRICS.reset_interfaces();
+        let mut prediction_stats = PredictionStats::default();
+        let prober = Prober::new(ProbePhase::Main);
+        for packet in &main {
+            match prober.parse_at(&packet.data, ignore_auth, packet.time_ms as u16) {
+                Ok(result) => {
+                    self.recv_responses_main.fetch_add(1, SeqCst);
+                    Self::probing_callback(
+                        &self.targets,
+                        &mut backward_stop_set,
+                        &HashMap::new(),
+                        &mut forward_discovery_set,
+                        &mut prediction_stats,
+                        &result,
+                    );
+                    let _ = topo_tx.send(TopoReq::Result(result));
+                }
+                Err(e) => METRICS.on_recv_error(&e),
+            }
+        }
+        let _ = topo_tx.send(TopoReq::Stop);
+        prediction_stats.report();
+        self.count_interfaces(&backward_stop_set, &forward_discovery_set);
+        let results = topo.run().await;
+
+        self.summarize(start_time);
+
+        Ok(results)
+    }
+
+    fn summarize(&self, start_time: SystemTime) {
         let end_time = SystemTime::now();
+        self.start_time_ms.store(Self::unix_ms(start_time), SeqCst);
+        self.end_time_ms.store(Self::unix_ms(end_time), SeqCst);
 
         log::info!(
             "[Summary] Pre: sent {:?}, recv {:?};  Main: sent {:?}, recv {:?}",
@@ -166,31 +434,159 @@ impl Tracerouter {
             self.sent_probes,
             self.recv_responses_main
         );
+        let recv_errors = METRICS.recv_errors();
+        if !recv_errors.is_empty() {
+            let errors: Vec<_> = recv_errors
+                .iter()
+                .map(|((variant, detail), count)| {
+                    if detail.is_empty() {
+                        format!("{} {}", variant, count)
+                    } else {
+                        format!("{}({}) {}", variant, detail, count)
+                    }
+                })
+                .collect();
+            log::info!("[Summary] Discarded replies: {}", errors.join(", "));
+        }
+        if self.invalid_target_lines > 0 {
+            log::info!(
+                "[Summary] Invalid lines skipped in target list: {}",
+                self.invalid_target_lines
+            );
+        }
         log::info!(
             "[Summary] Elapsed: {} secs",
             end_time.duration_since(start_time).unwrap().as_secs()
         );
+        if self.partial() {
+            log::warn!("[Summary] Scan is not completed, results are partial");
+        }
         log::info!(
             "[Summary] Interfaces: forward {}, backward {}, total {}",
             self.forward_count.load(SeqCst),
             self.backward_count.load(SeqCst),
             self.total_count.load(SeqCst),
         );
+    }
 
-        Ok(topo)
+    fn unix_ms(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+
+    /// Metadata of this run, which makes the results self-describing.
+    pub fn manifest(&self) -> serde_json::Value {
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "vantage": {
+                "interface": OPT.interface.name,
+                "address": OPT.local_addr.to_string(),
+                "source_addrs": OPT
+                    .source_addrs
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>(),
+            },
+            "options": OPT.to_json(),
+            "args": OPT.effective_args,
+            "seed": OPT.seed,
+            "start_time_ms": self.start_time_ms.load(SeqCst),
+            "end_time_ms": self.end_time_ms.load(SeqCst),
+            "partial": self.partial(),
+            "targets": self.targets.len(),
+            "invalid_target_lines": self.invalid_target_lines,
+            "phases": {
+                "pre": {
+                    "sent": self.sent_preprobes.load(SeqCst),
+                    "recv": self.recv_responses_pre.load(SeqCst),
+                },
+                "main": {
+                    "sent": self.sent_probes.load(SeqCst),
+                    "recv": self.recv_responses_main.load(SeqCst),
+                },
+            },
+            "interfaces": {
+                "forward": self.forward_count.load(SeqCst),
+                "backward": self.backward_count.load(SeqCst),
+                "total": self.total_count.load(SeqCst),
+            },
+        })
     }
 
     pub fn stop(&self) {
         self.stopped.store(true, SeqCst);
     }
 
-    fn stopped(&self) -> bool {
+    /// Hold scheduling of probes while the scan is paused through the control API.
+    async fn wait_if_paused(&self) {
+        while CONTROL.paused() && !self.stopped() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    pub fn stopped(&self) -> bool {
         self.stopped.load(SeqCst)
     }
+
+    /// Whether the results are incomplete, since the scan is stopped or runs out of budget.
+    pub fn partial(&self) -> bool {
+        self.stopped() || self.budget_exceeded.load(SeqCst)
+    }
+
+    /// Check `--max-probes` and `--max-duration`, where `scheduled` is the count of probes
+    /// scheduled in the current phase.
+    fn exceeds_budget(&self, start_time: SystemTime, scheduled: u64) -> bool {
+        let out_of_probes = match OPT.max_probes {
+            Some(max) => self.sent_preprobes.load(SeqCst) + scheduled >= max,
+            None => false,
+        };
+        let out_of_time = match OPT.max_duration {
+            Some(max) => {
+                SystemTime::now().duration_since(start_time).unwrap() >= Duration::from_secs(max)
+            }
+            None => false,
+        };
+        let exceeded = out_of_probes || out_of_time;
+
+        if exceeded && !self.budget_exceeded.swap(true, SeqCst) {
+            log::warn!("Budget exceeded, stop scheduling probes and produce partial results.");
+        }
+        exceeded
+    }
+
+    /// Cap `wait` at the time left of `max_duration` seconds since `start_time`, so that waiting
+    /// for replies does not overrun `--max-duration`.
+    fn wait_within_budget(
+        start_time: SystemTime,
+        wait: Duration,
+        max_duration: Option<u64>,
+    ) -> Duration {
+        match max_duration {
+            Some(max) => {
+                let elapsed = SystemTime::now()
+                    .duration_since(start_time)
+                    .unwrap_or_default();
+                let left = Duration::from_secs(max).checked_sub(elapsed);
+                wait.min(left.unwrap_or_default())
+            }
+            None => wait,
+        }
+    }
 }
 
 impl Tracerouter {
-    async fn run_preprobing_task(&self) -> Result<()> {
+    /// Progress bar of scheduling, kept quiet while the TUI is on screen.
+    fn progress_bar(total: usize) -> pbr::ProgressBar<Box<dyn Write + Send>> {
+        let out: Box<dyn Write + Send> = if tui::active() {
+            Box::new(std::io::sink())
+        } else {
+            Box::new(std::io::stdout())
+        };
+        let mut pb = pbr::ProgressBar::on(out, total as u64);
+        pb.set_max_refresh_rate(Some(Duration::from_millis(100)));
+        pb
+    }
+
+    async fn run_preprobing_task(&self, start_time: SystemTime) -> Result<()> {
         let prober = Prober::new(ProbePhase::Pre);
         let (recv_tx, mut recv_rx) = mpsc::unbounded_channel();
         let mut nm = NetworkManager::new(prober, recv_tx)?;
@@ -211,37 +607,47 @@ impl Tracerouter {
         });
 
         // WORKER BEGIN
-        let mut pb = pbr::ProgressBar::new(self.targets.len() as u64);
-        pb.set_max_refresh_rate(Some(Duration::from_millis(100)));
-        for target in self.targets.values() {
+        let keys = Self::permute_keys(self.targets.keys().cloned().collect(), OPT.seed);
+        CONTROL.begin_phase(Stage::Preprobing, keys.len());
+        let mut pb = Self::progress_bar(keys.len());
+        let total_count = keys.len();
+        for (scheduled, key) in keys.into_iter().enumerate() {
             pb.inc();
-            if self.stopped() {
+            self.wait_if_paused().await;
+            if self.stopped() || self.exceeds_budget(start_time, scheduled as u64) {
                 break;
             }
+            CONTROL.set_round(1, total_count - scheduled);
+            let target = self.targets.get(&key).unwrap();
             nm.schedule_probe((target.addr, OPT.preprobing_ttl)).await;
         }
         pb.finish();
         // WORKER END
 
+        CONTROL.set_round(1, 0);
+        CONTROL.set_stage(Stage::Waiting);
         if !self.stopped() {
-            log::info!("[Pre] Waiting for 3 secs...");
-            tokio::time::sleep(Duration::from_secs(3)).await;
+            let wait = Self::wait_within_budget(
+                start_time,
+                Duration::from_secs(OPT.preprobing_wait),
+                OPT.max_duration,
+            );
+            log::info!("[Pre] Waiting for {:.1} secs...", wait.as_secs_f64());
+            nm.wait_for_replies(wait).await;
         }
         nm.stop();
         let _ = stop_tx.send(());
 
-        let preprobed_count = {
-            let mut c = 0u64;
-            for _ in self
-                .targets
-                .values()
-                .filter(|dcb| dcb.preprobed.load(SeqCst))
-            {
-                c += 1;
+        let shared = match &self.worker {
+            Some(worker) => {
+                worker
+                    .exchange_distances(&self.measured_distances(), &self.stopped)
+                    .await
             }
-            c
+            None => Vec::new(),
         };
-        log::info!("Preprobed: {}", preprobed_count);
+        self.propagate_proximity(&shared, OPT.prefixes.as_ref());
+        self.report_split_ttls();
 
         self.sent_preprobes.fetch_add(nm.sent_packets(), SeqCst);
         self.recv_responses_pre.fetch_add(nm.recv_packets(), SeqCst);
@@ -249,81 +655,201 @@ impl Tracerouter {
         Ok(())
     }
 
-    fn preprobing_callback(targets: &DcbMap, result: ProbeResult) {
-        if !result.from_destination {
-            return;
+    fn report_split_ttls(&self) {
+        let mut confidence_counts = HashMap::<Confidence, u64>::new();
+        for dcb in self.targets.values() {
+            *confidence_counts.entry(dcb.confidence()).or_insert(0) += 1;
         }
+        let count_of = |c| confidence_counts.get(&c).cloned().unwrap_or(0);
+        log::info!(
+            "Preprobed: {};  exact {}, reply ttl {}, lower bound {}, proximity {}",
+            self.targets.len() as u64 - count_of(Confidence::None),
+            count_of(Confidence::Exact),
+            count_of(Confidence::ReplyTtl),
+            count_of(Confidence::LowerBound),
+            count_of(Confidence::Proximity),
+        );
+    }
+
+    /// Distances measured in preprobing, rather than predicted.
+    fn measured_distances(&self) -> Vec<SharedDistance> {
+        self.targets
+            .iter()
+            .filter(|(_, dcb)| dcb.confidence() >= Confidence::ReplyTtl)
+            .map(|(&key, dcb)| (key, dcb.initial_ttl(), dcb.confidence()))
+            .collect()
+    }
+
+    /// Predict the distance of each target not measured from its neighbors within
+    /// `proximity_span` keys, including those measured by other workers in `shared`.
+    /// Measurements are weighted by the inverse square of their distance in key space so that
+    /// the nearest one is preferred, and those across a boundary of `prefixes` are ignored if
+    /// provided.
+    fn propagate_proximity(&self, shared: &[SharedDistance], prefixes: Option<&PrefixMap>) {
+        let span = OPT.proximity_span as AddrKey;
+
+        // snapshot measured distances, so that predicted ones are never propagated again
+        let measured: HashMap<AddrKey, (u8, Confidence)> = self
+            .measured_distances()
+            .into_iter()
+            .chain(shared.iter().cloned())
+            .map(|(key, ttl, confidence)| (key, (ttl, confidence)))
+            .collect();
+
+        let mut propagated_count = 0u64;
+        for (&key, dcb) in self.targets.iter() {
+            if dcb.confidence() >= Confidence::LowerBound {
+                continue;
+            }
+
+            let mut weight_sum = 0f64;
+            let mut ttl_sum = 0f64;
+            for d in 1..=span {
+                for &n_key in &[key - d, key + d] {
+                    if let Some(&(ttl, confidence)) = measured.get(&n_key) {
+                        if let Some(prefixes) = prefixes {
+                            let n_addr = match self.targets.get(&n_key) {
+                                Some(n_dcb) => n_dcb.addr,
+                                None => Self::key_to_addr(n_key),
+                            };
+                            if !prefixes.same_prefix(dcb.addr, n_addr) {
+                                continue;
+                            }
+                        }
+                        let mut weight = 1.0 / (d * d) as f64;
+                        if confidence == Confidence::Exact {
+                            weight *= 2.0;
+                        }
+                        weight_sum += weight;
+                        ttl_sum += weight * ttl as f64;
+                    }
+                }
+            }
+
+            if weight_sum > 0.0 {
+                let ttl = (ttl_sum / weight_sum).round() as u8;
+                dcb.update_split_ttl(ttl.max(1).min(OPT.max_ttl), Confidence::Proximity);
+                propagated_count += 1;
+            }
+        }
+        log::info!("Predicted by proximity: {}", propagated_count);
+    }
+
+    fn reuse_distances(&self, known: &HashMap<AddrKey, (u8, Confidence)>) {
+        for (key, &(ttl, confidence)) in known {
+            if let Some(dcb) = self.targets.get(key) {
+                dcb.update_split_ttl(ttl, confidence);
+            }
+        }
+        log::info!(
+            "[Pre] Skipped, reusing {} split ttls from the previous cycle",
+            known.len()
+        );
+    }
+
+    fn preprobing_callback(targets: &DcbMap, result: ProbeResult) {
         log::trace!("[Pre] CALLBACK: {}", result.destination);
 
+        let (distance, confidence) = if result.from_destination && !result.distance_inferred {
+            (result.distance, Confidence::Exact)
+        } else if result.from_destination {
+            (result.distance, Confidence::ReplyTtl)
+        } else if result.distance >= OPT.preprobing_ttl {
+            // time exceeded at the preprobing ttl, the destination is even farther
+            (OPT.preprobing_ttl, Confidence::LowerBound)
+        } else {
+            return;
+        };
+        let distance = distance.max(1).min(OPT.max_ttl);
+
         let key = Self::addr_to_key(result.destination);
         if let Some(dcb) = targets.get(&key) {
-            dcb.update_split_ttl(result.distance, true);
-
-            // proximity
-            let lo = 0.max(key - OPT.proximity_span as AddrKey);
-            let hi = key + OPT.proximity_span as AddrKey;
-            for n_key in lo..=hi {
-                if n_key == key {
-                    continue;
-                }
-                if let Some(dcb) = targets.get(&n_key) {
-                    dcb.update_split_ttl(result.distance, false);
-                }
-            }
+            dcb.update_split_ttl(distance, confidence);
         }
     }
 }
 
 impl Tracerouter {
-    async fn run_probing_task(&self) -> Result<TopoGraph> {
+    async fn run_probing_task(&self, start_time: SystemTime) -> Result<Results> {
         let prober = Prober::new(ProbePhase::Main);
         let (recv_tx, mut recv_rx) = mpsc::unbounded_channel();
         let mut nm = NetworkManager::new(prober, recv_tx)?;
         let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
 
         let targets = self.targets.clone();
-        let mut backward_stop_set = HashSet::<Ipv4Addr>::with_capacity(1_100_000);
+        // interface => the destination through which it's first discovered
+        let mut backward_stop_set = HashMap::<Ipv4Addr, Ipv4Addr>::with_capacity(1_100_000);
         let mut forward_discovery_set = HashSet::<Ipv4Addr>::with_capacity(200_000);
+        METRICS.reset_interfaces();
+        let mut prediction_stats = PredictionStats::default();
 
         let (topo_tx, topo_rx) = mpsc::unbounded_channel();
         let cb_topo_tx = topo_tx.clone();
 
-        let topo_task = tokio::spawn(async move { Topo::new(topo_rx).run() });
+        let results_path = self.results_path.clone();
+        let topo_task =
+            tokio::spawn(async move { Topo::new(topo_rx, OPT.local_addr, results_path).run() });
+
+        let worker = self.worker.clone();
+        // backward stops discovered by other workers
+        let mut shared_stops = HashMap::<Ipv4Addr, Ipv4Addr>::new();
+        let mut shared_stops_rx = match worker.as_ref().and_then(|w| w.take_stops()) {
+            Some(rx) => rx,
+            None => mpsc::unbounded_channel().1,
+        };
 
         let callback_task = tokio::spawn(async move {
             loop {
                 tokio::select! {
                     Some(result) = recv_rx.recv() => {
-                        Self::probing_callback(&targets, &mut backward_stop_set, &mut forward_discovery_set, &result);
+                        // workers on the same host receive replies to each other
+                        if worker.is_some() && !targets.contains_key(&Self::addr_to_key(result.destination)) {
+                            continue;
+                        }
+                        let stop_count = backward_stop_set.len();
+                        Self::probing_callback(&targets, &mut backward_stop_set, &shared_stops, &mut forward_discovery_set, &mut prediction_stats, &result);
+                        if let Some(worker) = &worker {
+                            if backward_stop_set.len() > stop_count {
+                                worker.share_stop(result.responder, backward_stop_set[&result.responder]);
+                            }
+                        }
                         let _ = cb_topo_tx.send(TopoReq::Result(result));
                     }
+                    Some(stops) = shared_stops_rx.recv() => {
+                        shared_stops.extend(stops);
+                    }
                     _ = &mut stop_rx => {
                         let _ = cb_topo_tx.send(TopoReq::Stop);
                         break;
                     }
                 };
             }
-            return (backward_stop_set, forward_discovery_set);
+            return (backward_stop_set, forward_discovery_set, prediction_stats);
         });
 
         // WORKER BEGIN
         let mut keys: Vec<_> = self.targets.keys().cloned().collect();
+        CONTROL.begin_phase(Stage::Probing, keys.len());
         let mut last_seen = SystemTime::now();
-        let one_sec = Duration::from_secs(1);
+        let max_round_duration = Duration::from_millis(OPT.max_round_ms);
 
         let mut round = 0usize;
-        while !keys.is_empty() {
+        let mut scheduled = 0u64;
+        while !keys.is_empty() && !self.budget_exceeded.load(SeqCst) {
             round += 1;
+            keys = Self::permute_keys(keys, OPT.seed.wrapping_add(round as u64));
 
             let total_count = keys.len();
             let mut new_keys = Vec::with_capacity(total_count);
 
             log::trace!("[Main] loop");
-            let mut pb = pbr::ProgressBar::new(total_count as u64);
-            pb.set_max_refresh_rate(Some(Duration::from_millis(100)));
+            let mut pb = Self::progress_bar(total_count);
+            CONTROL.set_round(round, total_count);
             for key in keys {
                 pb.inc();
-                if self.stopped() {
+                self.wait_if_paused().await;
+                // each target may take up to 2 probes
+                if self.stopped() || self.exceeds_budget(start_time, scheduled + 1) {
                     break;
                 }
                 let dcb = self.targets.get(&key).unwrap();
@@ -331,10 +857,12 @@ impl Tracerouter {
                 let mut ok = true;
                 if let Some(t) = dcb.pull_backward_task() {
                     nm.schedule_probe((dcb.addr, t)).await;
+                    scheduled += 1;
                     ok = false;
                 }
                 if let Some(t) = dcb.pull_forward_task() {
                     nm.schedule_probe((dcb.addr, t)).await;
+                    scheduled += 1;
                     ok = false;
                 }
                 if !ok {
@@ -345,13 +873,23 @@ impl Tracerouter {
             keys = new_keys;
 
             let duration = SystemTime::now().duration_since(last_seen).unwrap();
-            let min_round_duration = one_sec.min(Duration::from_millis(keys.len() as u64 * 20));
+            let min_round_duration =
+                max_round_duration.min(match nm.reply_timing().rtt_quantile(OPT.rtt_quantile) {
+                    Some(rtt) if OPT.adaptive_wait => rtt,
+                    _ => Duration::from_millis(keys.len() as u64 * OPT.round_ms_per_target),
+                });
             if duration < min_round_duration {
-                tokio::time::sleep(min_round_duration - duration).await;
+                let wait = Self::wait_within_budget(
+                    start_time,
+                    min_round_duration - duration,
+                    OPT.max_duration,
+                );
+                tokio::time::sleep(wait).await;
             }
             last_seen = SystemTime::now();
 
             let remain_count = keys.len();
+            CONTROL.set_round(round, remain_count);
             log::info!(
                 "round {:3}: total {:8}, complete {:8}, remain {:8};  sent {:8}, recv {:8}",
                 round,
@@ -364,36 +902,119 @@ impl Tracerouter {
         }
         // WORKER END
 
+        CONTROL.set_stage(Stage::Waiting);
         if !self.stopped() {
-            log::info!("[Main] Waiting for 5 secs...");
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            let wait = Self::wait_within_budget(
+                start_time,
+                Duration::from_secs(OPT.probing_wait),
+                OPT.max_duration,
+            );
+            log::info!("[Main] Waiting for {:.1} secs...", wait.as_secs_f64());
+            nm.wait_for_replies(wait).await;
         }
         nm.stop();
         let _ = stop_tx.send(());
 
         log::info!("Generating statistics and topology...");
-        let (mut backward_set, forward_set) = callback_task.await.unwrap();
+        let (backward_set, forward_set, prediction_stats) = callback_task.await.unwrap();
+        prediction_stats.report();
 
         // stats
-        self.backward_count.store(backward_set.len() as u64, SeqCst);
-        self.forward_count.store(forward_set.len() as u64, SeqCst);
-        backward_set.extend(forward_set.iter());
-        self.total_count.store(backward_set.len() as u64, SeqCst);
+        self.count_interfaces(&backward_set, &forward_set);
 
         self.sent_probes.fetch_add(nm.sent_packets(), SeqCst);
         self.recv_responses_main
             .fetch_add(nm.recv_packets(), SeqCst);
 
-        Ok(topo_task.await.unwrap().await)
+        self.report_backward_stops(nm.sent_packets());
+        if let Some(path) = OPT.dump_stops.clone() {
+            log::info!("Dumping backward stops...");
+            self.dump_backward_stops(&path)?;
+        }
+
+        let results = topo_task.await.unwrap().await;
+        if let Some(worker) = &self.worker {
+            worker.send_results(&results).await;
+        }
+        Ok(results)
+    }
+
+    fn count_interfaces(
+        &self,
+        backward_set: &HashMap<Ipv4Addr, Ipv4Addr>,
+        forward_set: &HashSet<Ipv4Addr>,
+    ) {
+        self.backward_count.store(backward_set.len() as u64, SeqCst);
+        self.forward_count.store(forward_set.len() as u64, SeqCst);
+        let mut all_set: HashSet<Ipv4Addr> = backward_set.keys().cloned().collect();
+        all_set.extend(forward_set.iter());
+        self.total_count.store(all_set.len() as u64, SeqCst);
+    }
+
+    fn report_backward_stops(&self, sent_probes: u64) {
+        let mut reason_counts = HashMap::<BackwardStopReason, u64>::new();
+        let mut saved_probes = 0u64;
+        for dcb in self.targets.values() {
+            *reason_counts.entry(dcb.backward_stop_reason()).or_insert(0) += 1;
+            saved_probes += dcb.saved_probes() as u64;
+        }
+        let count_of = |r| reason_counts.get(&r).cloned().unwrap_or(0);
+        let baseline = sent_probes + saved_probes;
+
+        log::info!(
+            "[Summary] Backward stops: converged {}, exhausted {}, interrupted {}",
+            count_of(BackwardStopReason::Converged),
+            count_of(BackwardStopReason::Exhausted),
+            count_of(BackwardStopReason::Interrupted),
+        );
+        log::info!(
+            "[Summary] Redundancy removal: saved {} probes, {:.1}% of estimated {} without it",
+            saved_probes,
+            saved_probes as f64 * 100.0 / baseline.max(1) as f64,
+            baseline
+        );
+    }
+
+    fn dump_backward_stops(&self, path: &PathBuf) -> Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        for dcb in self.targets.values() {
+            let reason = dcb.backward_stop_reason();
+            if reason == BackwardStopReason::Converged {
+                file.write_fmt(format_args!(
+                    "{}\t{:?}\t{}\t{}\t{}\n",
+                    dcb.addr,
+                    reason,
+                    dcb.backward_stop_ttl(),
+                    dcb.converged_with(),
+                    dcb.saved_probes()
+                ))?;
+            } else {
+                file.write_fmt(format_args!("{}\t{:?}\n", dcb.addr, reason))?;
+            }
+        }
+
+        Ok(())
     }
 
     fn probing_callback(
         targets: &DcbMap,
-        backward_stop_set: &mut HashSet<Ipv4Addr>,
+        backward_stop_set: &mut HashMap<Ipv4Addr, Ipv4Addr>,
+        shared_stops: &HashMap<Ipv4Addr, Ipv4Addr>,
         forward_discovery_set: &mut HashSet<Ipv4Addr>,
+        prediction_stats: &mut PredictionStats,
         result: &ProbeResult,
     ) {
         log::trace!("[Main] CALLBACK: {}", result.destination);
+        if tui::active() {
+            METRICS.on_responder(result.responder);
+        }
+
+        let discovered = |backward_stop_set: &HashMap<Ipv4Addr, Ipv4Addr>,
+                          forward_discovery_set: &HashSet<Ipv4Addr>| {
+            backward_stop_set.contains_key(&result.responder)
+                || forward_discovery_set.contains(&result.responder)
+        };
+        let known = discovered(backward_stop_set, forward_discovery_set);
 
         let key = Self::addr_to_key(result.destination);
         if let Some(dcb) = targets.get(&key) {
@@ -402,12 +1023,24 @@ impl Tracerouter {
                 if result.distance > dcb.initial_ttl() {
                     // o-o-o-S-o-X-o-D
                     forward_discovery_set.insert(result.responder);
+                    METRICS
+                        .forward_interfaces
+                        .store(forward_discovery_set.len() as u64, SeqCst);
                 } else {
                     // o-X-o-S-o-o-o-D
-                    let new = backward_stop_set.insert(result.responder);
-                    if !new && OPT.redundancy_removal {
-                        log::trace!("STOP for {}", dcb.addr);
-                        dcb.stop_backward();
+                    let converged_with = backward_stop_set
+                        .get(&result.responder)
+                        .or_else(|| shared_stops.get(&result.responder));
+                    if let Some(&converged_with) = converged_with {
+                        if OPT.redundancy_removal {
+                            log::trace!("STOP for {}", dcb.addr);
+                            dcb.converge_backward(result.distance, converged_with);
+                        }
+                    } else {
+                        backward_stop_set.insert(result.responder, dcb.addr);
+                        METRICS
+                            .backward_interfaces
+                            .store(backward_stop_set.len() as u64, SeqCst);
                     }
                 }
                 if result.distance <= dcb.last_forward_task() {
@@ -416,12 +1049,22 @@ impl Tracerouter {
                 }
             } else {
                 // from destination
+                prediction_stats.observe(key, dcb, result.distance);
                 if !OPT.router_only {
-                    backward_stop_set.insert(result.responder);
+                    backward_stop_set
+                        .entry(result.responder)
+                        .or_insert(dcb.addr);
+                    METRICS
+                        .backward_interfaces
+                        .store(backward_stop_set.len() as u64, SeqCst);
                 }
                 dcb.stop_forward();
             }
         }
+
+        if !known && discovered(backward_stop_set, forward_discovery_set) {
+            METRICS.interfaces.fetch_add(1, SeqCst);
+        }
     }
 }
 
@@ -442,4 +1085,156 @@ mod test {
             panic!();
         }
     }
+
+    #[test]
+    fn test_secret_not_saved() {
+        let secret = OPT.probe_secret.unwrap().to_string();
+        let manifest = Tracerouter::new().unwrap().manifest().to_string();
+        assert!(!manifest.contains(&secret));
+        assert!(manifest.contains("<masked>"));
+        // as the comment of the dot file
+        assert!(!OPT.effective_args.join(" ").contains(&secret));
+        assert!(!format!("{:?}", *OPT).contains(&secret));
+    }
+
+    #[test]
+    fn test_wait_within_budget() {
+        let wait = Duration::from_secs(3);
+        let start_time = SystemTime::now() - Duration::from_secs(10);
+        assert_eq!(
+            Tracerouter::wait_within_budget(start_time, wait, None),
+            wait
+        );
+        assert_eq!(
+            Tracerouter::wait_within_budget(start_time, wait, Some(60)),
+            wait
+        );
+        let capped = Tracerouter::wait_within_budget(start_time, wait, Some(12));
+        assert!(capped <= Duration::from_secs(2) && capped > Duration::from_secs(1));
+        assert_eq!(
+            Tracerouter::wait_within_budget(start_time, wait, Some(5)),
+            Duration::from_secs(0)
+        );
+    }
+
+    fn dcb(addr: &str, ttl: u8, confidence: Confidence) -> (AddrKey, DstCtrlBlock) {
+        let addr: Ipv4Addr = addr.parse().unwrap();
+        let dcb = DstCtrlBlock::new(addr, OPT.split_ttl);
+        if confidence != Confidence::None {
+            dcb.update_split_ttl(ttl, confidence);
+        }
+        (Tracerouter::addr_to_key(addr), dcb)
+    }
+
+    fn propagated(
+        dcbs: Vec<(AddrKey, DstCtrlBlock)>,
+        shared: &[SharedDistance],
+        prefixes: Option<&PrefixMap>,
+        addr: &str,
+    ) -> (u8, Confidence) {
+        let tr = Tracerouter {
+            targets: Arc::new(dcbs.into_iter().collect()),
+            ..Default::default()
+        };
+        tr.propagate_proximity(shared, prefixes);
+        let dcb = tr.targets.values().find(|dcb| dcb.addr.to_string() == addr);
+        let dcb = dcb.unwrap();
+        (dcb.initial_ttl(), dcb.confidence())
+    }
+
+    #[test]
+    fn test_propagate_proximity() {
+        // weighted by the inverse square of the distance in keys: (10 * 1 + 20 / 4) / (1 + 1 / 4)
+        let dcbs = vec![
+            dcb("10.0.9.1", 10, Confidence::ReplyTtl),
+            dcb("10.0.10.1", 0, Confidence::None),
+            dcb("10.0.12.1", 20, Confidence::ReplyTtl),
+        ];
+        assert_eq!(
+            propagated(dcbs, &[], None, "10.0.10.1"),
+            (12, Confidence::Proximity)
+        );
+
+        // exact ones weigh double: (10 + 16 * 2) / 3
+        let dcbs = vec![
+            dcb("10.0.9.1", 10, Confidence::ReplyTtl),
+            dcb("10.0.10.1", 0, Confidence::None),
+            dcb("10.0.11.1", 16, Confidence::Exact),
+        ];
+        assert_eq!(
+            propagated(dcbs, &[], None, "10.0.10.1"),
+            (14, Confidence::Proximity)
+        );
+
+        // lower bounds are not propagated, nor overwritten
+        let dcbs = vec![
+            dcb("10.0.9.1", 10, Confidence::LowerBound),
+            dcb("10.0.10.1", 0, Confidence::None),
+            dcb("10.0.11.1", 20, Confidence::LowerBound),
+        ];
+        assert_eq!(
+            propagated(dcbs, &[], None, "10.0.10.1"),
+            (OPT.split_ttl, Confidence::None)
+        );
+    }
+
+    #[test]
+    fn test_propagate_proximity_prefixes() {
+        let prefixes = PrefixMap::parse("10.0.0.0/20\n10.0.16.0/20\n").unwrap();
+        let across = || {
+            let dcbs = vec![
+                dcb("10.0.14.1", 10, Confidence::ReplyTtl),
+                dcb("10.0.15.1", 0, Confidence::None),
+            ];
+            // measured by another worker, thus not a target here
+            let shared = [(
+                Tracerouter::addr_to_key("10.0.16.1".parse().unwrap()),
+                20,
+                Confidence::ReplyTtl,
+            )];
+            (dcbs, shared)
+        };
+
+        let (dcbs, shared) = across();
+        assert_eq!(
+            propagated(dcbs, &shared, None, "10.0.15.1"),
+            (15, Confidence::Proximity)
+        );
+        let (dcbs, shared) = across();
+        assert_eq!(
+            propagated(dcbs, &shared, Some(&prefixes), "10.0.15.1"),
+            (10, Confidence::Proximity)
+        );
+
+        // unannounced addresses are never in the same prefix
+        let dcbs = vec![
+            dcb("10.1.0.1", 0, Confidence::None),
+            dcb("10.1.1.1", 10, Confidence::Exact),
+        ];
+        assert_eq!(
+            propagated(dcbs, &[], Some(&prefixes), "10.1.0.1"),
+            (OPT.split_ttl, Confidence::None)
+        );
+    }
+
+    #[test]
+    fn test_prediction_stats() {
+        let mut stats = PredictionStats::default();
+        let (key, exact) = dcb("10.0.0.1", 10, Confidence::Exact);
+        stats.observe(key, &exact, 10);
+        // observed once per target
+        stats.observe(key, &exact, 12);
+        let (key, reply_ttl) = dcb("10.0.1.1", 12, Confidence::ReplyTtl);
+        stats.observe(key, &reply_ttl, 10);
+        let (key, proximity) = dcb("10.0.2.1", 10, Confidence::Proximity);
+        stats.observe(key, &proximity, 13);
+        let (key, proximity) = dcb("10.0.3.1", 13, Confidence::Proximity);
+        stats.observe(key, &proximity, 13);
+
+        let by_confidence = |c| stats.by_confidence.get(&c).cloned();
+        assert_eq!(by_confidence(Confidence::Exact), Some((1, 1, 0)));
+        assert_eq!(by_confidence(Confidence::ReplyTtl), Some((1, 0, 2)));
+        assert_eq!(by_confidence(Confidence::Proximity), Some((2, 1, 3)));
+        assert_eq!(by_confidence(Confidence::LowerBound), None);
+    }
 }
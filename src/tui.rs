@@ -0,0 +1,363 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, Write},
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{control::CONTROL, metrics::METRICS, OPT};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+const FRAMES_PER_SAMPLE: u64 = 4;
+const HISTORY_LENGTH: usize = 60;
+const TOP_RESPONDERS: usize = 5;
+const MAX_ERRORS: usize = 5;
+const RECENT_LOG_LINES: usize = 5;
+const MAX_LOG_LINES: usize = 1000;
+const LINE_WIDTH: usize = 78;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Log lines held back while the TUI is on screen, printed after it closes.
+    static ref LOG_LINES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+}
+
+/// Whether the TUI is on screen, so that progress bars and log lines must keep quiet.
+pub fn active() -> bool {
+    ACTIVE.load(SeqCst)
+}
+
+/// Logger holding records back while the TUI is on screen, and delegating them otherwise.
+pub struct Logger(env_logger::Logger);
+
+impl Logger {
+    pub fn new(inner: env_logger::Logger) -> Self {
+        Self(inner)
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let mut lines = LOG_LINES.lock().unwrap();
+        if active() {
+            if lines.len() == MAX_LOG_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(format!("{:<5} {}", record.level(), record.args()));
+            return;
+        }
+        drop(lines);
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Everything shown on a frame of the dashboard.
+#[derive(Debug, Default)]
+struct Snapshot {
+    stage: String,
+    round: u64,
+    total_keys: u64,
+    remaining_keys: u64,
+    elapsed_ms: u64,
+    paused: bool,
+    sent: u64,
+    recv: u64,
+    current_rate: u64,
+    probing_rate: u64,
+    queue_depth: u64,
+    forward_interfaces: u64,
+    backward_interfaces: u64,
+    interfaces: u64,
+    top_responders: Vec<(Ipv4Addr, u64)>,
+    errors: Vec<(String, u64)>,
+}
+
+impl Snapshot {
+    fn take() -> Self {
+        let counters = CONTROL.counters();
+        let errors = METRICS
+            .recv_errors()
+            .into_iter()
+            .map(|((variant, detail), count)| {
+                if detail.is_empty() {
+                    (variant.to_owned(), count)
+                } else {
+                    (format!("{}({})", variant, detail), count)
+                }
+            })
+            .collect();
+
+        Self {
+            stage: CONTROL.stage().name().to_owned(),
+            round: CONTROL.round() as u64,
+            total_keys: CONTROL.total_keys() as u64,
+            remaining_keys: CONTROL.remaining_keys() as u64,
+            elapsed_ms: CONTROL.elapsed_ms(),
+            paused: CONTROL.paused(),
+            sent: counters.sent,
+            recv: counters.recv,
+            current_rate: CONTROL.sending_rate.rate(),
+            probing_rate: match CONTROL.probing_rate() {
+                u64::MAX => 0,
+                rate => rate,
+            },
+            queue_depth: METRICS.queue_depth(),
+            forward_interfaces: METRICS.forward_interfaces.load(SeqCst),
+            backward_interfaces: METRICS.backward_interfaces.load(SeqCst),
+            interfaces: METRICS.interfaces.load(SeqCst),
+            top_responders: METRICS.top_responders(TOP_RESPONDERS),
+            errors,
+        }
+    }
+}
+
+fn sparkline(history: &VecDeque<u64>) -> String {
+    let max = history.iter().copied().max().unwrap_or(0);
+    history
+        .iter()
+        .map(|&v| {
+            SPARKS[(v * (SPARKS.len() as u64 - 1))
+                .checked_div(max)
+                .unwrap_or(0) as usize]
+        })
+        .collect()
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Render a frame, with the interface counts sampled every second in `history` and the recent
+/// held back log lines in `log`.
+fn render(snapshot: &Snapshot, history: &VecDeque<u64>, log: &[String]) -> String {
+    const BAR_WIDTH: u64 = 30;
+
+    let mut lines = Vec::new();
+    let secs = snapshot.elapsed_ms / 1000;
+    lines.push(format!(
+        "\x1b[1m{}\x1b[0m  {}, round {}, elapsed {:02}:{:02}:{:02}{}",
+        env!("CARGO_PKG_NAME"),
+        snapshot.stage,
+        snapshot.round,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        if snapshot.paused {
+            "  \x1b[7m PAUSED \x1b[0m"
+        } else {
+            ""
+        }
+    ));
+    lines.push(String::new());
+
+    let done = snapshot.total_keys.saturating_sub(snapshot.remaining_keys);
+    let filled = (done * BAR_WIDTH)
+        .checked_div(snapshot.total_keys)
+        .unwrap_or(0);
+    lines.push(format!(
+        "Targets     {} / {}  [{}{}] {:.1}%",
+        done,
+        snapshot.total_keys,
+        "#".repeat(filled as usize),
+        ".".repeat((BAR_WIDTH - filled) as usize),
+        percent(done, snapshot.total_keys)
+    ));
+    let target = match snapshot.probing_rate {
+        0 => "unlimited".to_owned(),
+        rate => format!("{} pps, {:.1}%", rate, percent(snapshot.current_rate, rate)),
+    };
+    lines.push(format!(
+        "Rate        {} pps (target {}), queue {}",
+        snapshot.current_rate, target, snapshot.queue_depth
+    ));
+    lines.push(format!(
+        "Replies     recv {} / sent {} ({:.1}%)",
+        snapshot.recv,
+        snapshot.sent,
+        percent(snapshot.recv, snapshot.sent)
+    ));
+    lines.push(format!(
+        "Interfaces  forward {}, backward {}, total {}",
+        snapshot.forward_interfaces, snapshot.backward_interfaces, snapshot.interfaces
+    ));
+    lines.push(format!("            {}", sparkline(history)));
+
+    lines.push(String::new());
+    lines.push("\x1b[1mTop responders\x1b[0m".to_owned());
+    for (responder, count) in &snapshot.top_responders {
+        lines.push(format!("  {:<16} {}", responder, count));
+    }
+    lines.push(String::new());
+    lines.push("\x1b[1mDiscarded replies\x1b[0m".to_owned());
+    for (error, count) in snapshot.errors.iter().take(MAX_ERRORS) {
+        lines.push(format!("  {:<32} {}", error, count));
+    }
+    lines.push(String::new());
+    lines.push("\x1b[1mLog\x1b[0m".to_owned());
+    for line in log {
+        lines.push(format!(
+            "  {}",
+            line.chars().take(LINE_WIDTH).collect::<String>()
+        ));
+    }
+
+    // overwrite in place to avoid flickering
+    let mut frame = "\x1b[H".to_owned();
+    for line in lines {
+        let _ = writeln!(frame, "{}\x1b[K", line);
+    }
+    frame.push_str("\x1b[J");
+    frame
+}
+
+/// A full-screen dashboard of the running scan, which closes and prints the held back log lines
+/// on drop.
+pub struct Tui {
+    closed: Arc<AtomicBool>,
+}
+
+impl Tui {
+    /// Take over the terminal if `--tui` is given and stdout is a TTY.
+    pub fn start() -> Option<Self> {
+        if !OPT.tui {
+            return None;
+        }
+        if !atty::is(atty::Stream::Stdout) {
+            log::warn!("Stdout is not a TTY, fall back to progress bars and log lines");
+            return None;
+        }
+
+        {
+            let _lines = LOG_LINES.lock().unwrap();
+            ACTIVE.store(true, SeqCst);
+        }
+        // alternate screen, hide cursor
+        let mut out = io::stdout();
+        let _ = out.write_all(b"\x1b[?1049h\x1b[?25l");
+        let _ = out.flush();
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_cloned = closed.clone();
+        tokio::spawn(async move {
+            let mut history = VecDeque::with_capacity(HISTORY_LENGTH);
+            // frames until the next sample of the history
+            let mut frames_to_sample = 0;
+            loop {
+                if frames_to_sample == 0 {
+                    if history.len() == HISTORY_LENGTH {
+                        history.pop_front();
+                    }
+                    history.push_back(METRICS.interfaces.load(SeqCst));
+                    frames_to_sample = FRAMES_PER_SAMPLE;
+                }
+                frames_to_sample -= 1;
+                let log: Vec<_> = {
+                    let lines = LOG_LINES.lock().unwrap();
+                    let skip = lines.len().saturating_sub(RECENT_LOG_LINES);
+                    lines.iter().skip(skip).cloned().collect()
+                };
+                let frame = render(&Snapshot::take(), &history, &log);
+
+                {
+                    let out = io::stdout();
+                    let mut out = out.lock();
+                    if closed_cloned.load(SeqCst) {
+                        break;
+                    }
+                    let _ = out.write_all(frame.as_bytes());
+                    let _ = out.flush();
+                }
+
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+            }
+        });
+
+        Some(Self { closed })
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        {
+            // wait for the frame being drawn
+            let out = io::stdout();
+            let mut out = out.lock();
+            self.closed.store(true, SeqCst);
+            // show cursor, main screen
+            let _ = out.write_all(b"\x1b[?25h\x1b[?1049l");
+            let _ = out.flush();
+        }
+
+        let mut lines = LOG_LINES.lock().unwrap();
+        ACTIVE.store(false, SeqCst);
+        for line in lines.drain(..) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        let history: VecDeque<_> = vec![0, 7, 14].into_iter().collect();
+        assert_eq!(sparkline(&history), "▁▄█");
+        let history: VecDeque<_> = vec![0, 0].into_iter().collect();
+        assert_eq!(sparkline(&history), "▁▁");
+    }
+
+    #[test]
+    fn test_render() {
+        let snapshot = Snapshot {
+            stage: "probing".to_owned(),
+            round: 3,
+            total_keys: 200,
+            remaining_keys: 50,
+            elapsed_ms: 3_723_000,
+            sent: 1000,
+            recv: 250,
+            current_rate: 900,
+            probing_rate: 1000,
+            forward_interfaces: 3,
+            backward_interfaces: 4,
+            interfaces: 5,
+            top_responders: vec![("10.0.0.1".parse().unwrap(), 42)],
+            errors: vec![("UnexpectedIcmpType(3/0)".to_owned(), 7)],
+            ..Snapshot::default()
+        };
+        let frame = render(&snapshot, &VecDeque::new(), &["WARN  hello".to_owned()]);
+        assert!(frame.contains("probing, round 3, elapsed 01:02:03"));
+        assert!(frame.contains("150 / 200"));
+        assert!(frame.contains("75.0%"));
+        assert!(frame.contains("900 pps (target 1000 pps, 90.0%)"));
+        assert!(frame.contains("recv 250 / sent 1000 (25.0%)"));
+        assert!(frame.contains("forward 3, backward 4, total 5"));
+        assert!(frame.contains("10.0.0.1"));
+        assert!(frame.contains("UnexpectedIcmpType(3/0)"));
+        assert!(frame.contains("WARN  hello"));
+    }
+}
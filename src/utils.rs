@@ -34,6 +34,13 @@ pub fn get_interface(name: &str) -> Result<NetworkInterface> {
     }
 }
 
+pub fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 pub fn timestamp_ms_u16() -> u16 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -45,6 +52,31 @@ pub fn ip_checksum(addr: Ipv4Addr, salt: u16) -> u16 {
     pnet::util::checksum(&addr.octets(), 0) + salt
 }
 
+/// Guess the initial TTL of a reply as the nearest common default (64, 128 or 255) not less than
+/// the received one, which also hints the OS of the responder.
+pub fn infer_initial_ttl(reply_ttl: u8) -> u8 {
+    [64u8, 128, 255]
+        .iter()
+        .cloned()
+        .find(|&t| t >= reply_ttl)
+        .unwrap()
+}
+
+/// Guess the hop count of a reply by its inferred initial TTL.
+pub fn infer_distance(reply_ttl: u8) -> u8 {
+    infer_initial_ttl(reply_ttl) - reply_ttl + 1
+}
+
+/// Path in the temp dir unique to this process, so that concurrent test runs never clash.
+#[cfg(test)]
+pub fn test_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!(
+        "flashroute_rs_test_{}_{}",
+        std::process::id(),
+        name
+    ))
+}
+
 pub fn ensure_su() {
     if sudo::check() == sudo::RunningAs::User {
         log::warn!(
@@ -82,3 +114,22 @@ impl GlobalIpv4Ext for Ipv4Addr {
             && self.octets()[0] != 0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_infer_distance() {
+        assert_eq!(infer_initial_ttl(0), 64);
+        assert_eq!(infer_initial_ttl(64), 64);
+        assert_eq!(infer_initial_ttl(65), 128);
+        assert_eq!(infer_initial_ttl(255), 255);
+
+        assert_eq!(infer_distance(64), 1);
+        assert_eq!(infer_distance(65), 64);
+        assert_eq!(infer_distance(128), 1);
+        assert_eq!(infer_distance(255), 1);
+        assert_eq!(infer_distance(0), 65);
+    }
+}